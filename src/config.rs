@@ -0,0 +1,770 @@
+use std::{
+    collections::HashMap, env, fmt::Debug, path::PathBuf, str::FromStr, sync::LazyLock,
+    time::Duration,
+};
+
+use image::{Rgb, imageops::FilterType};
+use log::LevelFilter;
+
+use crate::mappings::ENCODER_COUNT;
+
+/// Prefix shared by all the environment variables the plugin reads
+const ENV_PREFIX: &str = "AKP05_";
+
+pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::from_env);
+
+/// Runtime configuration, read once from `AKP05_*` environment variables.
+///
+/// Every option has a default, so the plugin works as-is with an empty environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `AKP05_IMAGE_CROP`: center-crop incoming images to the aspect ratio of the target button
+    pub image_crop: bool,
+    /// `AKP05_IMAGE_PADDING`: padding around the image, in percent of its shorter side (0-49)
+    pub image_padding: u32,
+    /// `AKP05_IMAGE_CORNER_RADIUS`: corner radius, in percent of the image's shorter side (0-50)
+    pub image_corner_radius: u32,
+    /// `AKP05_IMAGE_BACKGROUND`: `RRGGBB` color used for padding, cut-off corners and
+    /// transparent areas
+    pub image_background: Rgb<u8>,
+    /// `AKP05_IMAGE_DOWNSCALE_FILTER`: filter for shrinking oversized images, or `none`
+    pub image_downscale_filter: Option<FilterType>,
+    /// `AKP05_IMAGE_FORMATS`: whether images in formats other than JPEG are accepted
+    pub image_formats: FormatStrictness,
+    /// `AKP05_MAX_DATA_URL_KB`: images sent as data URLs longer than this many KiB are rejected
+    /// before decoding, so a misbehaving client can't make the plugin allocate huge buffers
+    pub max_data_url: usize,
+    /// `AKP05_STARTUP_IMAGES`: folder with images shown on every device as soon as it's connected
+    pub startup_images: Option<PathBuf>,
+    /// `AKP05_BRIGHTNESS_FILE`: JSON file the last brightness of each device is kept in across
+    /// restarts, `None` if there's no config folder to put it in
+    pub brightness_file: Option<PathBuf>,
+    /// `AKP05_IDLE_TIMEOUT_MS`: dim the panel once no input came in for this long
+    pub idle_timeout: Duration,
+    /// `AKP05_IDLE_BRIGHTNESS`: brightness the panel is dimmed to while idle
+    pub idle_brightness: u8,
+    /// `AKP05_IDLE_IMAGES`: folder with the images shown while idle, laid out like startup images
+    pub idle_images: Option<PathBuf>,
+    /// `AKP05_BUTTON_SIZES`: per-device button image sizes, as `id=WIDTHxHEIGHT,...`
+    pub button_sizes: HashMap<String, (usize, usize)>,
+    /// `AKP05_IMAGE_MIRRORS`: per-device mirroring of button and touch zone images, as
+    /// `id=MIRRORING,...`, replacing the one of the device kind
+    pub image_mirrors: HashMap<String, Mirroring>,
+    /// `AKP05_IMAGE_TRANSITION_MS`: fade button images into each other over this long
+    pub image_transition: Duration,
+    /// `AKP05_CLEAR_ALL`: how the whole panel is cleared
+    pub clear_all: ClearAll,
+    /// `AKP05_CLEAR_ON_IMAGE_ERROR`: clear buttons the device library can't render an image on
+    pub clear_on_image_error: bool,
+    /// `AKP05_BMP_FALLBACK`: retry images the panel rejects as BMP
+    pub bmp_fallback: bool,
+    /// `AKP05_UNKNOWN_INPUT_LOG`: how unrecognized input codes are logged
+    pub unknown_input_log: UnknownInputLog,
+    /// `AKP05_TOUCH_TAPS`: pass touch zone taps on as encoder presses, rather than ignoring them
+    pub touch_taps: bool,
+    /// `AKP05_TWIST_BUTTONS`: encoders whose twists are sent as button presses instead, as
+    /// `encoder=LEFT:RIGHT,...` with OpenDeck button positions
+    pub twist_buttons: HashMap<u8, (u8, u8)>,
+    /// `AKP05_PROGRESS_BARS`: encoders whose touch zone shows a bar of their level, kept by the
+    /// plugin from their twists, instead of the images from OpenDeck
+    pub progress_bars: Vec<u8>,
+    /// `AKP05_TOUCH_ZONES`: touch zone showing the image of each encoder, in encoder order.
+    /// Empty for each encoder's own zone
+    pub touch_zones: Vec<u8>,
+    /// `AKP05_PROGRESS_BAR_STEP`: percent a progress bar level changes per detent
+    pub progress_bar_step: u8,
+    /// `AKP05_PROGRESS_BAR_COLOR`: `RRGGBB` color of the filled part of progress bars
+    pub progress_bar_color: Rgb<u8>,
+    /// `AKP05_PROGRESS_BAR_ORIENTATION`: which way progress bars fill up
+    pub progress_bar_orientation: BarOrientation,
+    /// `AKP05_ALL_RELEASED_EVENT`: log an event when the last held button on a panel is released
+    pub all_released_event: bool,
+    /// `AKP05_STUCK_INPUT_TIMEOUT_MS`: release everything held once the device has sent no
+    /// reports for this long, in case a release report got lost
+    pub stuck_input_timeout: Duration,
+    /// `AKP05_ENCODER_DEADZONE`: detents each encoder has to turn in quick succession before
+    /// twists are passed on, filtering out accidental brushes. 0 or 1 passes every twist
+    pub encoder_deadzone: [usize; ENCODER_COUNT],
+    /// `AKP05_TWIST_IMAGE_HOLDOFF_MS`: hold back touch zone images while their encoder is being
+    /// twisted, until it has rested this long, so input isn't slowed down by image writes
+    pub twist_image_holdoff: Duration,
+    /// `AKP05_HOLD_REPEAT_MS`: while an encoder is held down, send a clockwise twist this often
+    pub hold_repeat: Duration,
+    /// `AKP05_DEVICE_NAMES`: names shown in OpenDeck, as `selector=NAME,...` where the selector
+    /// is a device id, serial or HID device id
+    pub device_names: HashMap<String, String>,
+    /// `AKP05_CONNECT_BUTTON`: OpenDeck button position pressed once a device is registered, to
+    /// run whatever action is bound to it on connect
+    pub connect_button: Option<u8>,
+    /// `AKP05_NAME_SUFFIX`: when to add the end of the device id to the name shown in OpenDeck
+    pub name_suffix: NameSuffix,
+    /// `AKP05_SETTLE_MS`: wait after initializing a device before writing images, overriding the
+    /// delay of its kind
+    pub settle_delay: Option<Duration>,
+    /// `AKP05_RECONNECT_DEBOUNCE_MS`: keep a lost device registered with OpenDeck this long, in
+    /// case it comes right back
+    pub reconnect_debounce: Duration,
+    /// `AKP05_KEEP_IMAGES_ON_EXIT`: skip the device shutdown on exit, so panels keep their images
+    pub keep_images_on_exit: bool,
+    /// `AKP05_EXIT_BRIGHTNESS`: brightness set when the plugin lets go of a device
+    pub exit_brightness: Option<u8>,
+    /// `AKP05_ON_DISCONNECT`: what happens when the connection to OpenDeck drops
+    pub on_disconnect: DisconnectAction,
+    /// `AKP05_DISCONNECT_IMAGES`: folder with images shown while OpenDeck is gone, laid out like
+    /// `AKP05_STARTUP_IMAGES`
+    pub disconnect_images: Option<PathBuf>,
+    /// `AKP05_DEVICE_QUEUE_SIZE`: how many OpenDeck events can wait for each device.
+    ///
+    /// Once full, OpenDeck's event handler waits for the device to catch up. A larger queue
+    /// absorbs bursts (e.g. a page switch) without blocking, at the cost of memory for the
+    /// queued images and of stale updates piling up behind a slow device.
+    pub device_queue_size: usize,
+    /// `AKP05_DEFAULT_BRIGHTNESS`: brightness set when a device is connected for the first time
+    pub default_brightness: u8,
+    /// `AKP05_MIN_BRIGHTNESS`: brightness requests below this are raised to it, so panels never go dark
+    pub min_brightness: u8,
+    /// `AKP05_BRIGHTNESS_RAMP_MS`: fade brightness changes over this long instead of jumping
+    pub brightness_ramp: Duration,
+    /// `AKP05_ONLY_DEVICE`: manage just the device with this id, serial or HID device id
+    pub only_device: Option<String>,
+    /// `AKP05_RESTART_PANICKED`: start the task of a device again after it panicked
+    pub restart_panicked: bool,
+    /// `AKP05_UNVERIFIED_KINDS`: also look for kinds whose ids aren't confirmed with hardware
+    pub unverified_kinds: bool,
+    /// `AKP05_MAX_DEVICES`: devices found beyond this many are ignored
+    pub max_devices: usize,
+    /// `AKP05_DEVICE_IDS`: fixed ids for devices, as `selector=ID,...` where the selector is
+    /// `VID:PID` in hex or a HID device id
+    pub device_ids: HashMap<String, String>,
+    /// `AKP05_DISABLED_DEVICES`: comma-separated ids or serials of devices to leave alone
+    pub disabled_devices: Vec<String>,
+    /// `AKP05_CONTROL_SOCKET`: path of a Unix socket taking commands from other tools
+    pub control_socket: Option<PathBuf>,
+    /// `AKP05_ORIENTATION`: how the panels are mounted, rotating images and inputs together
+    pub orientation: Orientation,
+}
+
+/// How images that aren't JPEG are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatStrictness {
+    /// `strict`: reject them, to catch misconfigured clients
+    Strict,
+    /// `lenient`: decode PNG and BMP too, they're sent as JPEG like any other image
+    Lenient,
+    /// `warn`: like `lenient`, but log a warning for each one
+    Warn,
+}
+
+impl FromStr for FormatStrictness {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            "warn" => Ok(Self::Warn),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Logging behavior for input codes `process_input` doesn't recognize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownInputLog {
+    /// `warn`: warn on every occurrence
+    Warn,
+    /// `debug`: log every occurrence at debug level only
+    Debug,
+    /// `once-per-minute`: warn at most once per minute for each distinct code
+    OncePerMinute,
+}
+
+impl FromStr for UnknownInputLog {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(Self::Warn),
+            "debug" => Ok(Self::Debug),
+            "once-per-minute" => Ok(Self::OncePerMinute),
+            _ => Err(()),
+        }
+    }
+}
+
+/// When device names in OpenDeck get a part of the device id appended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameSuffix {
+    /// `auto`: only while more than one device of the same kind is connected
+    Auto,
+    /// `always`
+    Always,
+    /// `never`
+    Never,
+}
+
+impl FromStr for NameSuffix {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the plugin does when the connection to OpenDeck drops, e.g. because it crashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectAction {
+    /// `exit`: let go of the devices and exit, like on SIGTERM
+    Exit,
+    /// `hold`: leave the panels as they are and keep trying to reconnect
+    Hold,
+    /// `blank`: clear the panels and keep trying to reconnect
+    Blank,
+    /// `images`: show the `AKP05_DISCONNECT_IMAGES` and keep trying to reconnect
+    Images,
+}
+
+impl FromStr for DisconnectAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "exit" => Ok(Self::Exit),
+            "hold" => Ok(Self::Hold),
+            "blank" => Ok(Self::Blank),
+            "images" => Ok(Self::Images),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Direction progress bars on touch zones fill up in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarOrientation {
+    /// `horizontal`: from left to right
+    Horizontal,
+    /// `vertical`: from bottom to top
+    Vertical,
+}
+
+impl FromStr for BarOrientation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "horizontal" => Ok(Self::Horizontal),
+            "vertical" => Ok(Self::Vertical),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the whole panel is cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearAll {
+    /// `command`: with the device's own clear-all command
+    Command,
+    /// `each`: button by button, skipping the hardware indices the kind doesn't use
+    Each,
+}
+
+impl FromStr for ClearAll {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "command" => Ok(Self::Command),
+            "each" => Ok(Self::Each),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Mirroring applied to images on top of their rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    /// `none`
+    None,
+    /// `horizontal`: left and right swapped
+    Horizontal,
+    /// `vertical`: top and bottom swapped
+    Vertical,
+    /// `both`
+    Both,
+}
+
+impl FromStr for Mirroring {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "horizontal" => Ok(Self::Horizontal),
+            "vertical" => Ok(Self::Vertical),
+            "both" => Ok(Self::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Physical mounting of the panels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    /// `normal`: as the device sits on a desk
+    Normal,
+    /// `upside-down`: rotated by 180°, e.g. with the cable coming out of the other side
+    UpsideDown,
+}
+
+impl FromStr for Orientation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "upside-down" => Ok(Self::UpsideDown),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let config = Self {
+            image_crop: var("IMAGE_CROP", false),
+            image_padding: var("IMAGE_PADDING", 0u32).min(49),
+            image_corner_radius: var("IMAGE_CORNER_RADIUS", 0u32).min(50),
+            image_background: var("IMAGE_BACKGROUND", Color(Rgb([0, 0, 0]))).0,
+            image_downscale_filter: var(
+                "IMAGE_DOWNSCALE_FILTER",
+                Filter(Some(FilterType::Lanczos3)),
+            )
+            .0,
+            image_formats: var("IMAGE_FORMATS", FormatStrictness::Lenient),
+            max_data_url: var("MAX_DATA_URL_KB", 2048usize).max(1) * 1024,
+            startup_images: string("STARTUP_IMAGES").map(PathBuf::from),
+            brightness_file: string("BRIGHTNESS_FILE")
+                .map(PathBuf::from)
+                .or_else(|| config_dir().map(|dir| dir.join("brightness.json"))),
+            idle_timeout: Duration::from_millis(var("IDLE_TIMEOUT_MS", 0u64)),
+            idle_brightness: percent("IDLE_BRIGHTNESS", 10),
+            idle_images: string("IDLE_IMAGES").map(PathBuf::from),
+            button_sizes: sizes("BUTTON_SIZES"),
+            image_mirrors: mirrors("IMAGE_MIRRORS"),
+            image_transition: Duration::from_millis(var("IMAGE_TRANSITION_MS", 0u64).min(2000)),
+            clear_all: var("CLEAR_ALL", ClearAll::Command),
+            clear_on_image_error: var("CLEAR_ON_IMAGE_ERROR", false),
+            bmp_fallback: var("BMP_FALLBACK", false),
+            unknown_input_log: var("UNKNOWN_INPUT_LOG", UnknownInputLog::Warn),
+            touch_taps: var("TOUCH_TAPS", true),
+            twist_buttons: twist_buttons("TWIST_BUTTONS"),
+            progress_bars: encoders("PROGRESS_BARS"),
+            touch_zones: touch_zones("TOUCH_ZONES"),
+            progress_bar_step: var("PROGRESS_BAR_STEP", 2u8).clamp(1, 100),
+            progress_bar_color: var("PROGRESS_BAR_COLOR", Color(Rgb([255, 255, 255]))).0,
+            progress_bar_orientation: var("PROGRESS_BAR_ORIENTATION", BarOrientation::Horizontal),
+            all_released_event: var("ALL_RELEASED_EVENT", false),
+            stuck_input_timeout: Duration::from_millis(
+                var("STUCK_INPUT_TIMEOUT_MS", 0u64).min(600000),
+            ),
+            encoder_deadzone: deadzones("ENCODER_DEADZONE"),
+            twist_image_holdoff: Duration::from_millis(
+                var("TWIST_IMAGE_HOLDOFF_MS", 0u64).min(2000),
+            ),
+            hold_repeat: Duration::from_millis(var("HOLD_REPEAT_MS", 0u64).min(2000)),
+            device_names: pairs("DEVICE_NAMES"),
+            connect_button: optional("CONNECT_BUTTON"),
+            name_suffix: var("NAME_SUFFIX", NameSuffix::Auto),
+            settle_delay: optional("SETTLE_MS").map(|ms: u64| Duration::from_millis(ms.min(5000))),
+            reconnect_debounce: Duration::from_millis(
+                var("RECONNECT_DEBOUNCE_MS", 0u64).min(10000),
+            ),
+            keep_images_on_exit: var("KEEP_IMAGES_ON_EXIT", false),
+            exit_brightness: optional("EXIT_BRIGHTNESS").map(|brightness: u8| brightness.min(100)),
+            on_disconnect: var("ON_DISCONNECT", DisconnectAction::Exit),
+            disconnect_images: string("DISCONNECT_IMAGES").map(PathBuf::from),
+            device_queue_size: var("DEVICE_QUEUE_SIZE", 32usize).max(1),
+            default_brightness: percent("DEFAULT_BRIGHTNESS", 50),
+            min_brightness: percent("MIN_BRIGHTNESS", 0),
+            brightness_ramp: Duration::from_millis(var("BRIGHTNESS_RAMP_MS", 0u64).min(5000)),
+            only_device: string("ONLY_DEVICE"),
+            restart_panicked: var("RESTART_PANICKED", true),
+            unverified_kinds: var("UNVERIFIED_KINDS", false),
+            max_devices: var("MAX_DEVICES", 16usize),
+            disabled_devices: list("DISABLED_DEVICES"),
+            device_ids: pairs("DEVICE_IDS"),
+            control_socket: string("CONTROL_SOCKET").map(PathBuf::from),
+            orientation: var("ORIENTATION", Orientation::Normal),
+        };
+
+        log::debug!("Using config: {:#?}", config);
+
+        config
+    }
+}
+
+/// Level logged unless `AKP05_LOG_LEVEL` says otherwise
+pub const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// `AKP05_LOG_LEVEL`: most verbose level that is logged, or the invalid value.
+///
+/// Read on its own rather than with `CONFIG`, since the logger has to be set up first for
+/// warnings about the rest of the config to show up, this one included.
+pub fn log_level() -> Result<LevelFilter, String> {
+    match string("LOG_LEVEL") {
+        Some(raw) => raw.parse().map_err(|_| raw),
+        None => Ok(DEFAULT_LOG_LEVEL),
+    }
+}
+
+/// Reads and parses `AKP05_{name}`, falling back to the default if it's unset or invalid
+fn var<T: FromStr + Debug>(name: &str, default: T) -> T {
+    let key = format!("{}{}", ENV_PREFIX, name);
+
+    let Ok(raw) = env::var(&key) else {
+        return default;
+    };
+
+    match raw.trim().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            log::warn!(
+                "Invalid value {:?} for {}, using default {:?}",
+                raw,
+                key,
+                default
+            );
+            default
+        }
+    }
+}
+
+/// Reads `AKP05_{name}` as a percentage, see `parse_percent`
+fn percent(name: &str, default: u8) -> u8 {
+    let key = format!("{}{}", ENV_PREFIX, name);
+
+    match env::var(&key) {
+        Ok(raw) => parse_percent(&key, &raw, default),
+        Err(_) => default,
+    }
+}
+
+/// Parses a percentage, clamping values over 100 and falling back to the default if it's
+/// not a number at all
+fn parse_percent(key: &str, raw: &str, default: u8) -> u8 {
+    let Ok(value) = raw.trim().parse::<u64>() else {
+        log::warn!(
+            "Invalid value {:?} for {}, using default {:?}",
+            raw,
+            key,
+            default
+        );
+        return default;
+    };
+
+    if value > 100 {
+        log::warn!("Value {} for {} is over 100, using 100", value, key);
+    }
+
+    value.min(100) as u8
+}
+
+/// Reads and parses `AKP05_{name}`, returning `None` if it's unset or invalid
+fn optional<T: FromStr>(name: &str) -> Option<T> {
+    let raw = string(name)?;
+
+    match raw.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            log::warn!(
+                "Invalid value {:?} for {}{}, ignoring it",
+                raw,
+                ENV_PREFIX,
+                name
+            );
+            None
+        }
+    }
+}
+
+/// Reads `AKP05_{name}` as a string, treating an empty value as unset
+fn string(name: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, name))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Per-user config folder of the plugin, following the conventions of the OS
+fn config_dir() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let dir = env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(target_os = "macos")]
+    let dir =
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"));
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    dir.map(|dir| dir.join("opendeck-akp05"))
+}
+
+/// Reads `AKP05_{name}` as a comma-separated list, skipping empty items
+fn list(name: &str) -> Vec<String> {
+    string(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads `AKP05_{name}` as a list of `key=value` pairs, skipping invalid ones
+fn pairs(name: &str) -> HashMap<String, String> {
+    let mut pairs = HashMap::new();
+
+    for item in list(name) {
+        match item.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                pairs.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => log::warn!(
+                "Invalid pair {:?} in {}{}, skipping",
+                item,
+                ENV_PREFIX,
+                name
+            ),
+        }
+    }
+
+    pairs
+}
+
+/// Reads `AKP05_{name}` as a list of encoder indices, skipping invalid ones
+fn encoders(name: &str) -> Vec<u8> {
+    let mut encoders = Vec::new();
+
+    for item in list(name) {
+        match item.parse() {
+            Ok(encoder) => encoders.push(encoder),
+            Err(_) => log::warn!(
+                "Invalid encoder {:?} in {}{}, skipping",
+                item,
+                ENV_PREFIX,
+                name
+            ),
+        }
+    }
+
+    encoders
+}
+
+/// Reads `AKP05_{name}` as one deadzone for all encoders or one for each of them, passing every
+/// twist if it's invalid
+fn deadzones(name: &str) -> [usize; ENCODER_COUNT] {
+    let items = list(name);
+    let parsed: Result<Vec<usize>, _> = items.iter().map(|item| item.parse()).collect();
+
+    match parsed.as_deref() {
+        Ok([]) => [1; ENCODER_COUNT],
+        Ok([deadzone]) => [*deadzone; ENCODER_COUNT],
+        Ok(deadzones) if deadzones.len() == ENCODER_COUNT => {
+            let mut all = [1; ENCODER_COUNT];
+            all.copy_from_slice(deadzones);
+            all
+        }
+        _ => {
+            log::warn!(
+                "Invalid value {:?} for {}{}, expected one number or {}, ignoring it",
+                items,
+                ENV_PREFIX,
+                name,
+                ENCODER_COUNT
+            );
+            [1; ENCODER_COUNT]
+        }
+    }
+}
+
+/// Reads `AKP05_{name}` as a touch zone for every encoder, ignoring it unless each zone is
+/// used exactly once
+fn touch_zones(name: &str) -> Vec<u8> {
+    let zones = encoders(name);
+
+    if zones.is_empty() {
+        return zones;
+    }
+
+    let mut sorted = zones.clone();
+    sorted.sort_unstable();
+
+    if !sorted.iter().copied().eq(0..ENCODER_COUNT as u8) {
+        log::warn!(
+            "{}{} must list each of the {} touch zones once, ignoring it",
+            ENV_PREFIX,
+            name,
+            ENCODER_COUNT
+        );
+        return Vec::new();
+    }
+
+    zones
+}
+
+/// Reads `AKP05_{name}` as a list of `key=MIRRORING` pairs, skipping invalid ones
+fn mirrors(name: &str) -> HashMap<String, Mirroring> {
+    let mut mirrors = HashMap::new();
+
+    for (key, value) in pairs(name) {
+        match value.parse() {
+            Ok(mirroring) => {
+                mirrors.insert(key, mirroring);
+            }
+            Err(_) => log::warn!(
+                "Invalid mirroring {:?} for {} in {}{}, skipping",
+                value,
+                key,
+                ENV_PREFIX,
+                name
+            ),
+        }
+    }
+
+    mirrors
+}
+
+/// Reads `AKP05_{name}` as a list of `key=WIDTHxHEIGHT` pairs, skipping invalid ones
+fn sizes(name: &str) -> HashMap<String, (usize, usize)> {
+    let mut sizes = HashMap::new();
+
+    for item in list(name) {
+        let size = item.split_once('=').and_then(|(key, size)| {
+            let (width, height) = size.trim().split_once('x')?;
+            let size = (width.parse().ok()?, height.parse().ok()?);
+
+            Some((key.trim().to_string(), size))
+        });
+
+        match size {
+            Some((key, size)) => {
+                sizes.insert(key, size);
+            }
+            None => log::warn!(
+                "Invalid size {:?} in {}{}, skipping",
+                item,
+                ENV_PREFIX,
+                name
+            ),
+        }
+    }
+
+    sizes
+}
+
+/// Reads `AKP05_{name}` as a list of `encoder=LEFT:RIGHT` pairs, skipping invalid ones
+fn twist_buttons(name: &str) -> HashMap<u8, (u8, u8)> {
+    let mut buttons = HashMap::new();
+
+    for item in list(name) {
+        let mapping = item.split_once('=').and_then(|(encoder, keys)| {
+            let (left, right) = keys.trim().split_once(':')?;
+            let keys = (left.trim().parse().ok()?, right.trim().parse().ok()?);
+
+            Some((encoder.trim().parse().ok()?, keys))
+        });
+
+        match mapping {
+            Some((encoder, keys)) => {
+                buttons.insert(encoder, keys);
+            }
+            None => log::warn!(
+                "Invalid encoder mapping {:?} in {}{}, skipping",
+                item,
+                ENV_PREFIX,
+                name
+            ),
+        }
+    }
+
+    buttons
+}
+
+/// Resize filter name as accepted by `AKP05_IMAGE_DOWNSCALE_FILTER`
+#[derive(Debug)]
+struct Filter(Option<FilterType>);
+
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let filter = match s.to_ascii_lowercase().as_str() {
+            "none" => None,
+            "nearest" => Some(FilterType::Nearest),
+            "triangle" => Some(FilterType::Triangle),
+            "catmullrom" => Some(FilterType::CatmullRom),
+            "gaussian" => Some(FilterType::Gaussian),
+            "lanczos3" => Some(FilterType::Lanczos3),
+            _ => return Err(()),
+        };
+
+        Ok(Filter(filter))
+    }
+}
+
+/// Hex color in `RRGGBB` form, with or without a leading `#`
+#[derive(Debug)]
+struct Color(Rgb<u8>);
+
+impl FromStr for Color {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+
+        if hex.len() != 6 || !hex.is_ascii() {
+            return Err(());
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ());
+
+        Ok(Color(Rgb([channel(0)?, channel(2)?, channel(4)?])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_in_range_is_kept() {
+        assert_eq!(parse_percent("AKP05_TEST", "0", 50), 0);
+        assert_eq!(parse_percent("AKP05_TEST", "100", 50), 100);
+        assert_eq!(parse_percent("AKP05_TEST", " 42 ", 50), 42);
+    }
+
+    #[test]
+    fn percent_over_100_is_clamped() {
+        assert_eq!(parse_percent("AKP05_TEST", "101", 50), 100);
+        assert_eq!(parse_percent("AKP05_TEST", "150", 50), 100);
+        assert_eq!(parse_percent("AKP05_TEST", "300", 50), 100);
+    }
+
+    #[test]
+    fn invalid_percent_uses_default() {
+        assert_eq!(parse_percent("AKP05_TEST", "-1", 50), 50);
+        assert_eq!(parse_percent("AKP05_TEST", "abc", 50), 50);
+        assert_eq!(parse_percent("AKP05_TEST", "", 50), 50);
+    }
+}
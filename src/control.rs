@@ -0,0 +1,205 @@
+use std::{
+    io::ErrorKind,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    DEVICES, TRACKER,
+    device::{
+        DeviceMessage, clamp_brightness, query_device_state, reregister_devices, send_to_device,
+    },
+    version::build_info,
+};
+
+/// Commands accepted on the control socket, one JSON object per line.
+///
+/// ```text
+/// {"command": "list"}
+/// {"command": "get-state", "device": "n4-ABC123"}
+/// {"command": "set-brightness", "device": "n4-ABC123", "brightness": 40}
+/// {"command": "test-pattern", "device": "n4-ABC123"}
+/// {"command": "reregister"}
+/// {"command": "version"}
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum Command {
+    List,
+    GetState { device: String },
+    SetBrightness { device: String, brightness: u8 },
+    TestPattern { device: String },
+    Reregister,
+    Version,
+}
+
+/// Serves the control socket at `AKP05_CONTROL_SOCKET` until cancelled
+pub async fn control_task(path: PathBuf, token: CancellationToken) {
+    // A socket file left behind by a previous run would make binding fail. Anything else at
+    // the path is most likely a mistake in the config, so it's never removed
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(&path).ok();
+        }
+        Ok(_) => {
+            log::error!(
+                "Not binding control socket {}, the path exists and is not a socket",
+                path.display()
+            );
+            return;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            log::error!("Failed to check control socket {}: {}", path.display(), err);
+            return;
+        }
+    }
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to bind control socket {}: {}", path.display(), err);
+            return;
+        }
+    };
+
+    log::info!("Listening for commands on {}", path.display());
+
+    let tracker = TRACKER.lock().await.clone();
+
+    loop {
+        let stream = tokio::select! {
+            result = listener.accept() => result,
+            _ = token.cancelled() => break,
+        };
+
+        match stream {
+            Ok((stream, _)) => {
+                tracker.spawn(handle_connection(stream, token.clone()));
+            }
+            Err(err) => log::warn!("Failed to accept control connection: {}", err),
+        }
+    }
+
+    remove_socket(&path);
+
+    log::info!("Control socket is shutting down");
+}
+
+fn remove_socket(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        log::debug!(
+            "Could not remove control socket {}: {}",
+            path.display(),
+            err
+        );
+    }
+}
+
+/// Answers each command on a connection with a JSON line, `ok` telling if it succeeded
+async fn handle_connection(stream: UnixStream, token: CancellationToken) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line,
+            _ = token.cancelled() => break,
+        };
+
+        let line = match line {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                log::debug!("Control connection failed: {}", err);
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => {
+                log::info!("Control command: {:?}", command);
+                run_command(command).await
+            }
+            Err(err) => json!({ "ok": false, "error": format!("invalid command: {}", err) }),
+        };
+
+        let mut reply = reply.to_string();
+        reply.push('\n');
+
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn run_command(command: Command) -> Value {
+    match command {
+        Command::List => {
+            let devices: Vec<Value> = DEVICES
+                .read()
+                .await
+                .iter()
+                .map(|(id, handle)| json!({ "id": id, "kind": handle.kind.human_name() }))
+                .collect();
+
+            json!({ "ok": true, "devices": devices })
+        }
+        Command::GetState { device } => match query_device_state(&device).await {
+            Some(state) => json!({
+                "ok": true,
+                "brightness": state.brightness,
+                "panel_brightness": state.panel_brightness(),
+                "idle": state.idle,
+                "images": state.images,
+            }),
+            None => unknown_device(&device),
+        },
+        Command::SetBrightness { device, brightness } => {
+            if !DEVICES.read().await.contains_key(&device) {
+                return unknown_device(&device);
+            }
+
+            let brightness = clamp_brightness(brightness);
+            send_to_device(&device, DeviceMessage::SetBrightness(brightness)).await;
+
+            json!({ "ok": true })
+        }
+        Command::TestPattern { device } => {
+            if !DEVICES.read().await.contains_key(&device) {
+                return unknown_device(&device);
+            }
+
+            send_to_device(&device, DeviceMessage::ShowTestPattern).await;
+
+            json!({ "ok": true })
+        }
+        Command::Reregister => {
+            reregister_devices().await;
+
+            json!({ "ok": true })
+        }
+        Command::Version => {
+            let mut reply = build_info();
+            reply["ok"] = json!(true);
+
+            reply
+        }
+    }
+}
+
+fn unknown_device(id: &str) -> Value {
+    json!({ "ok": false, "error": format!("unknown device: {}", id) })
+}
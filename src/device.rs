@@ -1,174 +1,1981 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
 use data_url::DataUrl;
-use image::load_from_memory_with_format;
-use mirajazz::{device::Device, error::MirajazzError, state::DeviceStateUpdate};
+use image::{DynamicImage, imageops::FilterType};
+use log::Level;
+use mirajazz::{
+    device::Device, error::MirajazzError, images::ImageRect, state::DeviceStateUpdate,
+    types::ImageFormat,
+};
 use openaction::{OUTBOUND_EVENT_MANAGER, SetImageEvent};
+use tokio::{
+    sync::mpsc,
+    time::{Instant, sleep, timeout},
+};
 use tokio_util::sync::CancellationToken;
 
-use crate::{
-    DEVICES, TOKENS,
-    mappings::{
-        COL_COUNT, CandidateDevice, DEVICE_TYPE, ENCODER_COUNT, KEY_COUNT, Kind, ROW_COUNT,
-    },
-};
+#[cfg(not(target_os = "windows"))]
+use crate::images::test_pattern;
+#[cfg(not(target_os = "windows"))]
+use tokio::sync::oneshot;
+
+use crate::{
+    DEVICES, TOKENS, TRACKER,
+    config::{CONFIG, ClearAll, DisconnectAction, FormatStrictness, NameSuffix},
+    images::{
+        ImageHints, apply_format_transforms, blend, decode_jpeg, decode_other, preprocess,
+        progress_bar, read_image_file, sniff_format, transcodable_format,
+    },
+    inputs::{EncoderLevels, HoldRepeat, IdleTimer, TwistActivity, TwistFilter, input_processor},
+    logging::log_limited,
+    mappings::{
+        CandidateDevice, DEVICE_TYPE, Kind, TOUCH_ZONE_COUNT, TouchZoneMode, hardware_button_index,
+        image_mirroring, oriented_position, touch_zone, zone_encoder,
+    },
+};
+
+/// How long a device gets to acknowledge shutdown before it's abandoned
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Delay before the first init retry, doubled after each failed attempt
+const INIT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const INIT_RETRY_DELAY_MAX: Duration = Duration::from_secs(30);
+
+/// How many bad input reports in a row make the reader start over
+const MAX_BAD_REPORTS: usize = 16;
+
+/// Interval between brightness steps when ramping
+const RAMP_STEP: Duration = Duration::from_millis(20);
+
+/// Delay between registering a device and pressing its `AKP05_CONNECT_BUTTON`
+const CONNECT_BUTTON_DELAY: Duration = Duration::from_millis(500);
+
+/// Interval between consistency checks of the device maps
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval between intermediate frames of an image transition
+const TRANSITION_STEP: Duration = Duration::from_millis(40);
+
+/// How many times a flush is attempted before the error is passed on
+const FLUSH_ATTEMPTS: usize = 3;
+
+/// Delay between flush attempts, long enough for a brief USB stall to clear
+const FLUSH_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Last brightness set on each device by id, restored when it connects again.
+///
+/// Kept per device rather than globally, so panels running at different levels each come
+/// back at their own, and saved to `AKP05_BRIGHTNESS_FILE` so they also do after a restart.
+static BRIGHTNESS: LazyLock<Mutex<HashMap<String, u8>>> =
+    LazyLock::new(|| Mutex::new(load_brightness()));
+
+/// How long brightness changes settle before they're saved, so dragging a slider in OpenDeck
+/// writes the file once instead of for every step
+const BRIGHTNESS_SAVE_DELAY: Duration = Duration::from_millis(500);
+
+/// Whether a save of `BRIGHTNESS` is already scheduled
+static BRIGHTNESS_SAVE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Reads the brightness saved by a previous run, if any
+fn load_brightness() -> HashMap<String, u8> {
+    match &CONFIG.brightness_file {
+        Some(path) => load_brightness_from(path),
+        None => HashMap::new(),
+    }
+}
+
+fn load_brightness_from(path: &Path) -> HashMap<String, u8> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(err) => {
+            log::warn!("Could not read {}: {}", path.display(), err);
+            return HashMap::new();
+        }
+    };
+
+    match serde_json::from_str::<HashMap<String, u8>>(&contents) {
+        // The file may have been edited by hand
+        Ok(brightness) => brightness
+            .into_iter()
+            .map(|(id, brightness)| (id, clamp_brightness(brightness)))
+            .collect(),
+        Err(err) => {
+            log::warn!(
+                "Ignoring invalid brightness file {}: {}",
+                path.display(),
+                err
+            );
+            HashMap::new()
+        }
+    }
+}
+
+/// Saves `BRIGHTNESS` once changes settled for `BRIGHTNESS_SAVE_DELAY`, off the runtime threads
+async fn schedule_brightness_save() {
+    let Some(path) = CONFIG.brightness_file.clone() else {
+        return;
+    };
+
+    if BRIGHTNESS_SAVE_PENDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let tracker = TRACKER.lock().await.clone();
+
+    tracker.spawn(async move {
+        sleep(BRIGHTNESS_SAVE_DELAY).await;
+
+        // Changes from here on schedule another save
+        BRIGHTNESS_SAVE_PENDING.store(false, Ordering::SeqCst);
+
+        let brightness = BRIGHTNESS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+        let file = path.clone();
+        let result = tokio::task::spawn_blocking(move || save_brightness_to(&file, &brightness));
+
+        if let Ok(Err(err)) = result.await {
+            log_limited!(
+                Level::Warn,
+                "brightness-file",
+                "Could not save brightness to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    });
+}
+
+/// Writes the brightness of every device, going through a temporary file so a crash midway
+/// doesn't leave a truncated one behind
+fn save_brightness_to(path: &Path, brightness: &HashMap<String, u8>) -> std::io::Result<()> {
+    let temporary = path.with_extension("json.tmp");
+
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&temporary, serde_json::to_vec_pretty(brightness)?))
+        .and_then(|()| std::fs::rename(&temporary, path))
+}
+
+/// Lowers a brightness to 100, as what the device does with values over that is undefined.
+///
+/// Applied to every brightness from outside of the plugin before it's passed on to a device.
+pub fn clamp_brightness(brightness: u8) -> u8 {
+    if brightness > 100 {
+        log_limited!(
+            Level::Warn,
+            "brightness",
+            "Lowering brightness {} to 100",
+            brightness
+        );
+    }
+
+    brightness.min(100)
+}
+
+/// Brightness a device starts with, its last one if it was connected before
+fn initial_brightness(id: &str) -> u8 {
+    BRIGHTNESS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(id)
+        .copied()
+        .unwrap_or(CONFIG.default_brightness)
+}
+
+/// Requests from OpenDeck event handlers to a device task
+#[derive(Debug)]
+pub enum DeviceMessage {
+    SetImage(ImageUpdate),
+    SetBrightness(u8),
+    /// Replies with a copy of the panel state, for the control socket and `SIGUSR1`
+    #[cfg(not(target_os = "windows"))]
+    GetState(oneshot::Sender<DeviceState>),
+    /// Fills the panel with a test pattern, independent of the OpenDeck profile
+    #[cfg(not(target_os = "windows"))]
+    ShowTestPattern,
+    /// The connection to OpenDeck dropped, see `AKP05_ON_DISCONNECT`
+    ConnectionLost,
+    /// OpenDeck is back, draws what the plugin draws on its own again
+    Reconnected,
+    /// Draws the progress bar of an encoder at the given level, see `AKP05_PROGRESS_BARS`
+    ShowProgressBar(u8, u8),
+    /// Input came in while idle, brings back the images from OpenDeck, see `AKP05_IDLE_TIMEOUT_MS`
+    Wake,
+}
+
+impl DeviceMessage {
+    /// Short name for logs, without the payload
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SetImage(_) => "set_image",
+            Self::SetBrightness(_) => "set_brightness",
+            #[cfg(not(target_os = "windows"))]
+            Self::GetState(_) => "get_state",
+            #[cfg(not(target_os = "windows"))]
+            Self::ShowTestPattern => "show_test_pattern",
+            Self::ConnectionLost => "connection_lost",
+            Self::Reconnected => "reconnected",
+            Self::ShowProgressBar(_, _) => "show_progress_bar",
+            Self::Wake => "wake",
+        }
+    }
+}
+
+/// State of the panel as last set by the device task
+#[derive(Debug, Clone)]
+pub struct DeviceState {
+    /// Last brightness requested for the panel, which it's restored to when waking up
+    pub brightness: u8,
+    /// Hardware indices of the buttons currently showing an image
+    pub images: BTreeSet<u8>,
+    /// Last image written to each button, kept to fade from when transitions are enabled
+    pub frames: HashMap<u8, DynamicImage>,
+    /// Index of the candidate format last taken by regular buttons, see `Kind::image_formats`
+    pub button_format: usize,
+    /// Index of the candidate format last taken by touch zone buttons
+    pub touch_zone_format: usize,
+    /// Whether the panel is idle, dimmed and showing the idle images if configured
+    pub idle: bool,
+    /// Images last sent by OpenDeck by `(is_encoder, position)`, restored when waking up.
+    /// Only kept with `AKP05_IDLE_TIMEOUT_MS` set
+    pub snapshot: HashMap<(bool, u8), String>,
+    /// Last level drawn for each progress bar, redrawn after the panel was cleared
+    pub bars: HashMap<u8, u8>,
+}
+
+impl DeviceState {
+    fn new(brightness: u8) -> Self {
+        Self {
+            brightness,
+            images: BTreeSet::new(),
+            frames: HashMap::new(),
+            button_format: 0,
+            touch_zone_format: 0,
+            idle: false,
+            snapshot: HashMap::new(),
+            bars: HashMap::new(),
+        }
+    }
+
+    /// Brightness the panel is at right now, lower than the requested one while idle
+    #[cfg(not(target_os = "windows"))]
+    pub fn panel_brightness(&self) -> u8 {
+        if self.idle {
+            CONFIG.idle_brightness
+        } else {
+            self.brightness
+        }
+    }
+}
+
+/// Image for a button or touch zone at an OpenDeck position, as sent by OpenDeck
+#[derive(Debug, Clone)]
+pub struct ImageUpdate {
+    device: String,
+    is_encoder: bool,
+    position: Option<u8>,
+    image: Option<String>,
+}
+
+impl From<SetImageEvent> for ImageUpdate {
+    fn from(evt: SetImageEvent) -> Self {
+        Self {
+            device: evt.device,
+            is_encoder: evt.controller.as_deref() == Some("Encoder"),
+            position: evt.position,
+            image: evt.image,
+        }
+    }
+}
+
+/// What the rest of the plugin knows about a running device.
+///
+/// The `Device` itself is owned by its task, everything else talks to it through `sender`.
+#[derive(Debug, Clone)]
+pub struct DeviceHandle {
+    pub kind: Kind,
+    /// Name from `AKP05_DEVICE_NAMES`, shown in OpenDeck instead of the kind's name
+    pub name: Option<String>,
+    /// Messages are applied in the order they are sent. Only touch zone images may wait, in
+    /// `hold_back`, which keeps the last one per zone, so the newest image always wins
+    pub sender: mpsc::Sender<DeviceMessage>,
+}
+
+/// Initializes a device and listens for events
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "device", skip_all, fields(device = %candidate.id))
+)]
+pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
+    log::info!("Running device task for {:?}", candidate);
+
+    let disabled = CONFIG
+        .disabled_devices
+        .iter()
+        .any(|selector| candidate.matches(selector));
+
+    if disabled {
+        // Keep the id reserved without touching the device, so another app can drive it
+        log::info!("Device {} is disabled, leaving it alone", candidate.id);
+
+        token.cancelled().await;
+        release_device(&candidate.id, &token).await;
+
+        return;
+    }
+
+    // The device may have been unplugged again before the task even started
+    if token.is_cancelled() {
+        log::info!("Device {} is already gone, skipping init", candidate.id);
+        release_device(&candidate.id, &token).await;
+
+        return;
+    }
+
+    // Wrap in a closure so we can use `?` operator
+    let init = async || -> Result<Device, MirajazzError> {
+        let device = connect(&candidate).await?;
+
+        candidate.kind.init_sequence(&device).await?;
+
+        device
+            .set_brightness(initial_brightness(&candidate.id))
+            .await?;
+        clear_all_buttons(&device).await?;
+        flush(&device).await?;
+
+        // Images written right away can get dropped while the panel is still settling
+        sleep(
+            CONFIG
+                .settle_delay
+                .unwrap_or_else(|| candidate.kind.settle_delay()),
+        )
+        .await;
+
+        Ok(device)
+    };
+
+    // A device that is busy at first, e.g. claimed by another process, may become free later,
+    // so init is retried until it works or the device goes away
+    let Some(device) = retry_init(&candidate.id, &token, init).await else {
+        release_device(&candidate.id, &token).await;
+
+        return;
+    };
+
+    log::info!("Registering device {}", candidate.id);
+    let name = custom_name(&candidate);
+    register_device(&candidate.id, &candidate.kind, name.as_deref()).await;
+
+    let (sender, receiver) = mpsc::channel(CONFIG.device_queue_size);
+    let handle = DeviceHandle {
+        kind: candidate.kind.clone(),
+        name,
+        sender,
+    };
+
+    DEVICES.write().await.insert(candidate.id.clone(), handle);
+
+    // The first panel got a plain name, which is ambiguous now that there's a second one
+    if CONFIG.name_suffix == NameSuffix::Auto && has_peers(&candidate.id, &candidate.kind).await {
+        reregister_devices().await;
+    }
+
+    if let Some(key) = CONFIG.connect_button {
+        let tracker = TRACKER.lock().await.clone();
+        tracker.spawn(press_connect_button(candidate.id.clone(), key));
+    }
+
+    let activity = TwistActivity::new(CONFIG.twist_image_holdoff);
+    let idle = IdleTimer::new(CONFIG.idle_timeout);
+
+    tokio::select! {
+        _ = device_events_task(&candidate, &device, &activity, &idle) => {},
+        _ = device_messages_task(&candidate, &device, &activity, &idle, receiver) => {},
+        _ = token.cancelled() => {}
+    };
+
+    log::info!("Shutting down device {:?}", candidate);
+
+    // A device that stopped responding must not hold up the rest of the shutdown
+    match timeout(SHUTDOWN_TIMEOUT, shutdown_device(&candidate, &device)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => log::warn!("Device {} failed to shut down: {}", candidate.id, err),
+        Err(_) => log::warn!(
+            "Device {} did not acknowledge shutdown in time",
+            candidate.id
+        ),
+    }
+
+    release_device(&candidate.id, &token).await;
+
+    log::info!("Device task finished for {:?}", candidate);
+}
+
+/// Presses and releases a button on behalf of a device that just connected, so whatever
+/// action is bound to it in OpenDeck runs as a startup action
+async fn press_connect_button(id: String, key: u8) {
+    // OpenDeck loads the profile of a device after it's registered
+    sleep(CONNECT_BUTTON_DELAY).await;
+
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        log::info!("Pressing button {} of {} on connect", key, id);
+
+        let result = match outbound.key_down(id.clone(), key).await {
+            Ok(()) => outbound.key_up(id.clone(), key).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            log::warn!("Could not press the connect button of {}: {}", id, err);
+        }
+    }
+}
+
+/// Winds down a device once its tasks have stopped, always in the same order.
+///
+/// The message receiver is dropped by then, so no image can be rendered in between. The
+/// buttons are cleared and flushed, the exit brightness is applied if configured, and the
+/// device is shut down. With `AKP05_KEEP_IMAGES_ON_EXIT`, only the brightness is changed.
+async fn shutdown_device(
+    candidate: &CandidateDevice,
+    device: &Device,
+) -> Result<(), MirajazzError> {
+    if CONFIG.keep_images_on_exit {
+        // Shutting the device down is what blanks the panel, so just let go of the handle
+        log::info!("Leaving images on device {}", candidate.id);
+    } else {
+        clear_all_buttons(device).await?;
+        flush(device).await?;
+    }
+
+    if let Some(brightness) = CONFIG.exit_brightness {
+        device.set_brightness(brightness).await?;
+    }
+
+    if !CONFIG.keep_images_on_exit {
+        device.shutdown().await?;
+    }
+
+    Ok(())
+}
+
+/// Registers the device with OpenDeck using the layout of its kind
+async fn register_device(id: &String, kind: &Kind, name: Option<&str>) {
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        let result = outbound
+            .register_device(
+                id.clone(),
+                display_name(id, kind, name).await,
+                kind.rows() as u8,
+                kind.cols() as u8,
+                kind.encoder_count() as u8,
+                DEVICE_TYPE,
+            )
+            .await;
+
+        if let Err(err) = result {
+            log::error!("Could not register device {} with OpenDeck: {}", id, err);
+        }
+    }
+}
+
+/// Name set for the device with `AKP05_DEVICE_NAMES`, by id, serial or HID device id
+fn custom_name(candidate: &CandidateDevice) -> Option<String> {
+    CONFIG
+        .device_names
+        .iter()
+        .find(|(selector, _)| candidate.matches(selector))
+        .map(|(_, name)| name.clone())
+}
+
+/// Name shown in OpenDeck: the custom one if set, otherwise the kind's name with the end of
+/// the id appended to tell panels of a kind apart
+async fn display_name(id: &str, kind: &Kind, name: Option<&str>) -> String {
+    if let Some(name) = name {
+        return name.to_string();
+    }
+
+    let suffix = match CONFIG.name_suffix {
+        NameSuffix::Auto => has_peers(id, kind).await,
+        NameSuffix::Always => true,
+        NameSuffix::Never => false,
+    };
+
+    if !suffix {
+        return kind.human_name();
+    }
+
+    let start = id.char_indices().rev().nth(3).map_or(0, |(i, _)| i);
+
+    format!("{} ({})", kind.human_name(), &id[start..])
+}
+
+/// Checks if other devices of the same kind are connected
+async fn has_peers(id: &str, kind: &Kind) -> bool {
+    DEVICES
+        .read()
+        .await
+        .iter()
+        .any(|(other, handle)| other != id && handle.kind == *kind)
+}
+
+/// Registers all connected devices again, so OpenDeck picks up changed registration parameters.
+///
+/// OpenDeck replaces an existing registration with the same id, so devices are not
+/// deregistered first, which would make OpenDeck drop them and their profiles in between.
+pub async fn reregister_devices() {
+    let devices: Vec<(String, DeviceHandle)> = DEVICES
+        .read()
+        .await
+        .iter()
+        .map(|(id, handle)| (id.clone(), handle.clone()))
+        .collect();
+
+    for (id, handle) in devices {
+        log::info!("Re-registering device {}", id);
+        register_device(&id, &handle.kind, handle.name.as_deref()).await;
+    }
+}
+
+/// Removes the device from OpenDeck, after `AKP05_RECONNECT_DEBOUNCE_MS` if set.
+///
+/// A flaky cable makes a device drop and come back within moments. If it's managed again
+/// by the time the window is over, OpenDeck just sees the new registration replace the old
+/// one instead of the device disappearing and reappearing.
+pub async fn deregister_device(id: &str) {
+    let debounce = CONFIG.reconnect_debounce;
+
+    if debounce.is_zero() {
+        log::info!("Deregistering device {}", id);
+        deregister_now(id).await;
+        return;
+    }
+
+    log::info!("Deregistering device {} in {:?}", id, debounce);
+
+    let id = id.to_string();
+    let tracker = TRACKER.lock().await.clone();
+
+    tracker.spawn(async move {
+        sleep(debounce).await;
+
+        if TOKENS
+            .read()
+            .await
+            .get(&id)
+            .is_some_and(|token| !token.is_cancelled())
+        {
+            log::info!("Device {} is back, keeping its registration", id);
+            return;
+        }
+
+        log::info!("Deregistering device {}", id);
+        deregister_now(&id).await;
+    });
+}
+
+async fn deregister_now(id: &str) {
+    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+        outbound.deregister_device(id.to_string()).await.ok();
+    }
+}
+
+/// Frees the id reserved by the watcher so the device can be picked up again on reconnect.
+///
+/// If the id was already handed over to a newer task (e.g. the device was unplugged and
+/// plugged back in while this one was shutting down), its entries are left alone.
+pub async fn release_device(id: &String, token: &CancellationToken) {
+    token.cancel();
+
+    {
+        let mut tokens = TOKENS.write().await;
+
+        if tokens.get(id).is_some_and(|other| !other.is_cancelled()) {
+            log::debug!(
+                "Device {} is owned by a newer task, keeping its entries",
+                id
+            );
+            return;
+        }
+
+        tokens.remove(id);
+    }
+
+    DEVICES.write().await.remove(id);
+}
+
+/// Cancels the task of a device and stops routing events to it, updating both maps together.
+///
+/// With `release`, the id is freed right away, e.g. for a device that was unplugged and may
+/// come back before its old task is done. Otherwise it stays reserved by the cancelled token
+/// until the task finishes and calls `release_device`.
+pub async fn cancel_device(id: &str, release: bool) {
+    {
+        let mut tokens = TOKENS.write().await;
+
+        let token = if release {
+            tokens.remove(id)
+        } else {
+            tokens.get(id).cloned()
+        };
+
+        if let Some(token) = token {
+            token.cancel();
+        }
+    }
+
+    DEVICES.write().await.remove(id);
+}
+
+/// Logs and fixes entries in `DEVICES` and `TOKENS` that don't belong together.
+///
+/// Every device in `DEVICES` needs a live token. Tokens without a device are fine while a
+/// device is initializing, disabled or shutting down, so those are only logged at debug.
+async fn reconcile_devices() {
+    let live: Vec<String> = TOKENS
+        .read()
+        .await
+        .iter()
+        .filter(|(id, token)| !id.starts_with('_') && !token.is_cancelled())
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    let orphans: Vec<String> = DEVICES
+        .read()
+        .await
+        .keys()
+        .filter(|id| !live.contains(id))
+        .cloned()
+        .collect();
+
+    for id in orphans {
+        log::warn!("Device {} has no running task, removing it", id);
+        DEVICES.write().await.remove(&id);
+    }
+
+    let devices = DEVICES.read().await;
+    let pending: Vec<&String> = live
+        .iter()
+        .filter(|id| !devices.contains_key(*id))
+        .collect();
+
+    if !pending.is_empty() {
+        log::debug!("Devices without a handle yet: {:?}", pending);
+    }
+}
+
+/// Checks `DEVICES` and `TOKENS` against each other every `RECONCILE_INTERVAL`
+pub async fn reconcile_task(token: CancellationToken) {
+    loop {
+        tokio::select! {
+            _ = sleep(RECONCILE_INTERVAL) => reconcile_devices().await,
+            _ = token.cancelled() => break,
+        }
+    }
+}
+
+/// Handles errors, returning true if should continue, returning false if an error is fatal
+pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
+    log_limited!(Level::Error, id, "Device {} error: {}", id, err);
+
+    // Some errors are not critical and can be ignored without sending disconnected event
+    match err {
+        // Rendering failed, the button keeps whatever it showed before
+        MirajazzError::ImageError(_) => return true,
+        // An input report was dropped, the reader tracks these to resync if they pile up
+        MirajazzError::BadData => return true,
+        _ => {}
+    }
+
+    deregister_device(id).await;
+
+    log::info!("Cancelling tasks for device {}", id);
+    cancel_device(id, false).await;
+
+    log::info!("Finished clean-up for {}", id);
+
+    false
+}
+
+pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
+    let result = Device::connect(
+        &candidate.dev,
+        candidate.kind.protocol_version(),
+        candidate.kind.key_count(),
+        candidate.kind.encoder_count(),
+    )
+    .await;
+
+    match result {
+        Ok(device) => Ok(device),
+        Err(e) => {
+            log::error!("Error while connecting to device: {e}");
+
+            Err(e)
+        }
+    }
+}
+
+/// Queues a message for the device task, dropping it if the device is gone
+pub async fn send_to_device(id: &String, message: DeviceMessage) {
+    let sender = DEVICES
+        .read()
+        .await
+        .get(id)
+        .map(|handle| handle.sender.clone());
+
+    let Some(sender) = sender else {
+        log::error!("Received event for unknown device: {}", id);
+        return;
+    };
+
+    if sender.send(message).await.is_err() {
+        log::warn!("Device {} is shutting down, dropping event", id);
+    }
+}
+
+/// Asks a device task for the current state of its panel
+#[cfg(not(target_os = "windows"))]
+pub async fn query_device_state(id: &String) -> Option<DeviceState> {
+    let sender = DEVICES.read().await.get(id)?.sender.clone();
+    let (reply, response) = oneshot::channel();
+
+    sender.send(DeviceMessage::GetState(reply)).await.ok()?;
+    response.await.ok()
+}
+
+/// Shows a test pattern on all connected devices, for support diagnostics
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub async fn show_test_patterns() {
+    let ids: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    for id in ids {
+        send_to_device(&id, DeviceMessage::ShowTestPattern).await;
+    }
+}
+
+/// Tells all connected devices that OpenDeck is gone, so they can apply `AKP05_ON_DISCONNECT`
+pub async fn notify_connection_lost() {
+    let ids: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    for id in ids {
+        send_to_device(&id, DeviceMessage::ConnectionLost).await;
+    }
+}
+
+/// Tells all connected devices that OpenDeck is back
+pub async fn notify_reconnected() {
+    let ids: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    for id in ids {
+        send_to_device(&id, DeviceMessage::Reconnected).await;
+    }
+}
+
+/// Logs the state of all connected devices, for support diagnostics
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub async fn log_device_states() {
+    let ids: Vec<String> = DEVICES.read().await.keys().cloned().collect();
+
+    if ids.is_empty() {
+        log::info!("No devices connected");
+    }
+
+    for id in ids {
+        match query_device_state(&id).await {
+            Some(state) => log::info!(
+                "Device {}: brightness {} (at {}{}), images on buttons {:?}",
+                id,
+                state.brightness,
+                state.panel_brightness(),
+                if state.idle { " while idle" } else { "" },
+                state.images
+            ),
+            None => log::info!("Device {} did not report its state", id),
+        }
+    }
+}
+
+/// Runs `init` until it succeeds, waiting longer after each failed attempt.
+///
+/// Returns `None` as soon as the token is cancelled, also in the middle of an attempt, so a
+/// device that went away isn't talked to in vain.
+async fn retry_init<T>(
+    id: &str,
+    token: &CancellationToken,
+    mut init: impl AsyncFnMut() -> Result<T, MirajazzError>,
+) -> Option<T> {
+    let mut attempt = 0;
+
+    loop {
+        let result = tokio::select! {
+            biased;
+            _ = token.cancelled() => {
+                log::info!("Device {} went away during init", id);
+                return None;
+            }
+            result = init() => result,
+        };
+
+        let err = match result {
+            Ok(value) => return Some(value),
+            Err(err) => err,
+        };
+
+        let delay = init_retry_delay(attempt);
+
+        log::error!(
+            "Device {} failed to initialize, retrying in {:?}: {}",
+            id,
+            delay,
+            err
+        );
+
+        tokio::select! {
+            biased;
+            _ = token.cancelled() => {
+                log::info!("Device {} went away before init was retried", id);
+                return None;
+            }
+            _ = sleep(delay) => {}
+        }
+
+        attempt += 1;
+    }
+}
+
+/// Delay after the failed init attempt with the given index, counting from 0
+fn init_retry_delay(attempt: u32) -> Duration {
+    INIT_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(INIT_RETRY_DELAY_MAX)
+}
+
+/// Handles requests from OpenDeck to the device, in the order they were sent.
+///
+/// Touch zone images for an encoder that is being twisted are held back, only the latest
+/// one for each zone, and written once the encoder has rested for `AKP05_TWIST_IMAGE_HOLDOFF_MS`.
+async fn device_messages_task(
+    candidate: &CandidateDevice,
+    device: &Device,
+    activity: &TwistActivity,
+    idle: &IdleTimer,
+    mut receiver: mpsc::Receiver<DeviceMessage>,
+) {
+    let mut state = DeviceState::new(initial_brightness(&candidate.id));
+
+    // Messages from OpenDeck queue up meanwhile, so they are applied on top of these
+    if let Some(dir) = &CONFIG.startup_images {
+        let result = show_folder_images(&candidate.id, device, &mut state, &receiver, dir).await;
+
+        if let Err(err) = result {
+            if !handle_error(&candidate.id, err).await {
+                return;
+            }
+        }
+    }
+
+    // Progress bars start out empty, OpenDeck images never reach their touch zones
+    for encoder in &CONFIG.progress_bars {
+        let result =
+            show_progress_bar(&candidate.id, device, &mut state, &receiver, *encoder, 0).await;
+
+        if let Err(err) = result {
+            if !handle_error(&candidate.id, err).await {
+                return;
+            }
+        }
+    }
+
+    let mut held: HashMap<u8, ImageUpdate> = HashMap::new();
+
+    loop {
+        if idle.enter() {
+            let result = enter_idle(&candidate.id, device, &mut state, &receiver).await;
+
+            if let Err(err) = result {
+                if !handle_error(&candidate.id, err).await {
+                    break;
+                }
+            }
+        }
+
+        // Images held back for an encoder that has rested go before anything newer
+        let message = if let Some(update) = take_rested(activity, &mut held) {
+            DeviceMessage::SetImage(update)
+        } else {
+            let rested = held
+                .keys()
+                .filter_map(|encoder| activity.remaining(*encoder))
+                .min();
+            let wait = [rested, idle.remaining()].into_iter().flatten().min();
+
+            let received = match wait {
+                Some(wait) => match timeout(wait, receiver.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+                None => receiver.recv().await,
+            };
+
+            let Some(message) = received else {
+                break;
+            };
+
+            match message {
+                DeviceMessage::SetImage(update) => match hold_back(activity, &mut held, update) {
+                    Some(update) => DeviceMessage::SetImage(update),
+                    None => continue,
+                },
+                message => message,
+            }
+        };
+
+        let result = handle_message(&candidate.id, device, &mut state, &receiver, message).await;
+
+        if let Err(err) = result {
+            if !handle_error(&candidate.id, err).await {
+                break;
+            }
+        }
+    }
+}
+
+/// Holds back a touch zone image while its encoder is being twisted, returning the update
+/// if it's to be handled right away instead
+fn hold_back(
+    activity: &TwistActivity,
+    held: &mut HashMap<u8, ImageUpdate>,
+    update: ImageUpdate,
+) -> Option<ImageUpdate> {
+    match update.position {
+        // Clearing everything supersedes whatever was held back
+        None if update.image.is_none() => {
+            held.clear();
+            Some(update)
+        }
+        // Once an image is held back for a zone, later ones queue behind it to keep their order
+        Some(encoder)
+            if update.is_encoder
+                && (held.contains_key(&encoder) || activity.remaining(encoder).is_some()) =>
+        {
+            log::debug!(
+                "Holding back image for encoder {} while it's twisted",
+                encoder
+            );
+            held.insert(encoder, update);
+            None
+        }
+        _ => Some(update),
+    }
+}
+
+/// Takes a held back touch zone image whose encoder has rested long enough
+fn take_rested(
+    activity: &TwistActivity,
+    held: &mut HashMap<u8, ImageUpdate>,
+) -> Option<ImageUpdate> {
+    let encoder = *held
+        .keys()
+        .find(|encoder| activity.remaining(**encoder).is_none())?;
+
+    held.remove(&encoder)
+}
+
+/// Applies a single request from OpenDeck to the device
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "message", skip_all, fields(message = message.name()))
+)]
+async fn handle_message(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    message: DeviceMessage,
+) -> Result<(), MirajazzError> {
+    match message {
+        DeviceMessage::SetImage(update) => handle_set_image(device, state, pending, update).await,
+        DeviceMessage::SetBrightness(brightness) => {
+            handle_set_brightness(id, device, state, brightness).await
+        }
+        #[cfg(not(target_os = "windows"))]
+        DeviceMessage::GetState(reply) => {
+            // The requester may have given up waiting, that's fine
+            reply.send(state.clone()).ok();
+            Ok(())
+        }
+        #[cfg(not(target_os = "windows"))]
+        DeviceMessage::ShowTestPattern => show_test_pattern(id, device, state, pending).await,
+        DeviceMessage::ConnectionLost => handle_connection_lost(id, device, state, pending).await,
+        DeviceMessage::Reconnected => handle_reconnected(id, device, state, pending).await,
+        DeviceMessage::ShowProgressBar(encoder, level) => {
+            show_progress_bar(id, device, state, pending, encoder, level).await
+        }
+        DeviceMessage::Wake => handle_wake(id, device, state, pending).await,
+    }
+}
+
+/// Handles events from device to OpenDeck
+async fn device_events_task(
+    candidate: &CandidateDevice,
+    device: &Device,
+    activity: &TwistActivity,
+    idle: &IdleTimer,
+) -> Result<(), MirajazzError> {
+    log::debug!("Connecting to {} for incoming events", candidate.id);
+
+    let mut reader = device.get_reader(input_processor(&candidate.kind));
+    let mut bad_reports = 0;
+
+    // Buttons and encoders held down, to tell when the panel goes back to all released and
+    // to release them if their release report got lost
+    let mut pressed = BTreeSet::new();
+    let mut encoders_down = BTreeSet::new();
+    let mut last_report = Instant::now();
+    let mut twists = TwistFilter::new(CONFIG.encoder_deadzone);
+    let mut repeats = HoldRepeat::default();
+    let mut levels = EncoderLevels::default();
+
+    log::info!("Reader is ready for {}", candidate.id);
+
+    loop {
+        // Some devices send empty reports as keep-alives, so this stays out of the info log
+        log::trace!("Reading updates...");
+
+        let stuck_timeout = CONFIG.stuck_input_timeout;
+        let holding = !pressed.is_empty() || !encoders_down.is_empty();
+
+        // Wake up in time for the next twist of a held encoder, or to release stuck inputs
+        let stuck_wait = (holding && !stuck_timeout.is_zero())
+            .then(|| stuck_timeout.saturating_sub(last_report.elapsed()));
+        let wait = [repeats.timeout(), stuck_wait].into_iter().flatten().min();
+
+        let mut updates = match reader.read(wait).await {
+            Ok(updates) => updates,
+            Err(e) => {
+                if matches!(e, MirajazzError::BadData) {
+                    bad_reports += 1;
+
+                    // Lots of garbage in a row likely means the reader lost track of report
+                    // boundaries, so start over with a fresh one
+                    if bad_reports >= MAX_BAD_REPORTS {
+                        log::warn!(
+                            "Got {} bad reports in a row from {}, resyncing reader",
+                            bad_reports,
+                            candidate.id
+                        );
+
+                        reader = device.get_reader(input_processor(&candidate.kind));
+                        bad_reports = 0;
+                    }
+                }
+
+                if !handle_error(&candidate.id, e).await {
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        bad_reports = 0;
+
+        if !updates.is_empty() {
+            last_report = Instant::now();
+
+            if idle.touch() {
+                send_to_device(&candidate.id, DeviceMessage::Wake).await;
+            }
+        } else if holding && !stuck_timeout.is_zero() && last_report.elapsed() >= stuck_timeout {
+            log::warn!(
+                "No reports from {} for {:?} with inputs held down, releasing them",
+                candidate.id,
+                stuck_timeout
+            );
+
+            // A fresh reader starts out all released, like OpenDeck after these
+            reader = device.get_reader(input_processor(&candidate.kind));
+
+            updates.extend(pressed.iter().map(|key| DeviceStateUpdate::ButtonUp(*key)));
+
+            for encoder in &encoders_down {
+                repeats.release(*encoder);
+                updates.push(DeviceStateUpdate::EncoderUp(*encoder));
+            }
+        }
+
+        for encoder in repeats.due() {
+            log::debug!("Repeating twist of held encoder {}", encoder);
+            updates.push(DeviceStateUpdate::EncoderTwist(encoder, 1));
+        }
+
+        for update in updates {
+            log::trace!("New update: {:#?}", update);
+
+            match update {
+                DeviceStateUpdate::ButtonDown(key) => {
+                    pressed.insert(key);
+                }
+                DeviceStateUpdate::ButtonUp(key) => {
+                    // OpenDeck has no event for this, so it's logged for tools following the log
+                    if pressed.remove(&key) && pressed.is_empty() && CONFIG.all_released_event {
+                        log::info!("EVENT AllReleased device={}", candidate.id);
+                    }
+                }
+                DeviceStateUpdate::EncoderTwist(encoder, val) if !twists.allow(encoder, val) => {
+                    continue;
+                }
+                DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                    activity.record(encoder);
+
+                    if let Some(level) = levels.apply(encoder, val) {
+                        let message = DeviceMessage::ShowProgressBar(encoder, level);
+                        send_to_device(&candidate.id, message).await;
+                    }
+                }
+                DeviceStateUpdate::EncoderDown(encoder) => {
+                    encoders_down.insert(encoder);
+                    repeats.press(encoder);
+                }
+                DeviceStateUpdate::EncoderUp(encoder) => {
+                    encoders_down.remove(&encoder);
+                    repeats.release(encoder);
+                }
+                _ => {}
+            }
+
+            let id = candidate.id.clone();
+
+            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
+                let result = match update {
+                    DeviceStateUpdate::ButtonDown(key) => outbound.key_down(id.clone(), key).await,
+                    DeviceStateUpdate::ButtonUp(key) => outbound.key_up(id.clone(), key).await,
+                    DeviceStateUpdate::EncoderDown(encoder) => {
+                        outbound.encoder_down(id.clone(), encoder).await
+                    }
+                    DeviceStateUpdate::EncoderUp(encoder) => {
+                        outbound.encoder_up(id.clone(), encoder).await
+                    }
+                    DeviceStateUpdate::EncoderTwist(encoder, val)
+                        if CONFIG.twist_buttons.contains_key(&encoder) =>
+                    {
+                        let (left, right) = CONFIG.twist_buttons[&encoder];
+                        let key = if val < 0 { left } else { right };
+
+                        // One press for every detent, for actions that only bind to buttons
+                        let mut result = Ok(());
+
+                        for _ in 0..val.unsigned_abs() {
+                            if result.is_ok() {
+                                result = outbound.key_down(id.clone(), key).await;
+                            }
+                            if result.is_ok() {
+                                result = outbound.key_up(id.clone(), key).await;
+                            }
+                        }
+
+                        result
+                    }
+                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
+                        outbound
+                            .encoder_change(id.clone(), encoder, val as i16)
+                            .await
+                    }
+                };
+
+                // The connection to OpenDeck may be gone, which is handled in `main`
+                if let Err(err) = result {
+                    log_limited!(
+                        Level::Warn,
+                        id,
+                        "Could not send {:?} of {} to OpenDeck: {}",
+                        update,
+                        id,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles brightness changes, applying the configured minimum brightness and ramp.
+///
+/// Requests are already capped at 100 by `clamp_brightness` when they come in.
+async fn handle_set_brightness(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    brightness: u8,
+) -> Result<(), MirajazzError> {
+    let floor = CONFIG.min_brightness;
+
+    let brightness = if brightness < floor {
+        log::debug!(
+            "Raising brightness {} to the minimum of {}",
+            brightness,
+            floor
+        );
+        floor
+    } else {
+        brightness
+    };
+
+    let steps = (CONFIG.brightness_ramp.as_millis() / RAMP_STEP.as_millis()) as i32;
+    let from = state.brightness;
+
+    match requested_brightness(state, brightness) {
+        Some(brightness) => {
+            if steps > 1 && brightness != from {
+                let (from, to) = (from as i32, brightness as i32);
+
+                for step in 1..steps {
+                    device
+                        .set_brightness((from + (to - from) * step / steps) as u8)
+                        .await?;
+                    sleep(RAMP_STEP).await;
+                }
+            }
+
+            device.set_brightness(brightness).await?;
+        }
+        None => {
+            log::debug!(
+                "Keeping {} dimmed, brightness {} applies on wake",
+                id,
+                brightness
+            );
+        }
+    }
+
+    let changed = BRIGHTNESS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id.to_string(), brightness)
+        != Some(brightness);
+
+    if changed {
+        schedule_brightness_save().await;
+    }
+
+    Ok(())
+}
+
+/// Decodes an image OpenDeck sent as a data URL, logging why if it can't be used
+fn load_image(id: &str, data_url: &str) -> Option<DynamicImage> {
+    // Decoding allocates the whole payload, so oversized ones are turned away up front
+    if data_url.len() > CONFIG.max_data_url {
+        log_limited!(
+            Level::Warn,
+            id,
+            "Rejecting image for {}: data URL of {} bytes exceeds the limit of {}",
+            id,
+            data_url.len(),
+            CONFIG.max_data_url
+        );
+        return None;
+    }
+
+    let url = match DataUrl::process(data_url) {
+        Ok(url) => url,
+        Err(err) => {
+            log_limited!(Level::Warn, id, "Malformed data URL for {}: {:?}", id, err);
+            return None;
+        }
+    };
+
+    // OpenDeck sends base64, anything else is decoded as percent-encoded data
+    let header = data_url.split(',').next().unwrap_or_default();
+    let encoding = if header.trim_end().to_ascii_lowercase().ends_with(";base64") {
+        "base64"
+    } else {
+        "percent"
+    };
+
+    log::debug!("Image for {} is {} encoded", id, encoding);
+
+    let (body, fragment) = match url.decode_to_vec() {
+        Ok(decoded) => decoded,
+        Err(err) => {
+            log_limited!(
+                Level::Warn,
+                id,
+                "Invalid {} data in data URL for {}: {:?}",
+                encoding,
+                id,
+                err
+            );
+            return None;
+        }
+    };
+
+    let hints = match fragment {
+        Some(fragment) => {
+            let fragment = fragment.to_percent_encoded();
+            log::debug!("Image for {} has fragment {:?}", id, fragment);
+
+            ImageHints::parse(&fragment)
+        }
+        None => ImageHints::default(),
+    };
+
+    // Going by the format hint if there's one
+    let format = hints
+        .format
+        .as_deref()
+        .unwrap_or(url.mime_type().subtype.as_str());
+
+    // Without a usable mime type, the payload itself tells what it is
+    let format = match CONFIG.image_formats {
+        FormatStrictness::Strict => format,
+        _ if format == "jpeg" || transcodable_format(format).is_some() => format,
+        _ => match sniff_format(&body) {
+            Some(sniffed) => {
+                log::debug!("Image for {} of type {} looks like {}", id, format, sniffed);
+                sniffed
+            }
+            None => format,
+        },
+    };
+
+    let decoded = match (format, transcodable_format(format), CONFIG.image_formats) {
+        ("jpeg", _, _) => decode_jpeg(&body),
+        (_, Some(other), FormatStrictness::Lenient) => decode_other(&body, other),
+        (_, Some(other), FormatStrictness::Warn) => {
+            log_limited!(
+                Level::Warn,
+                id,
+                "Got {} image for {}, converting it to JPEG",
+                format,
+                id
+            );
+            decode_other(&body, other)
+        }
+        _ => {
+            log_limited!(
+                Level::Error,
+                id,
+                "Incorrect image format for {}: {} (mime type {})",
+                id,
+                format,
+                url.mime_type()
+            );
+            return None;
+        }
+    };
+
+    match decoded {
+        Ok(image) => Some(image),
+        Err(err) => {
+            log_limited!(Level::Warn, id, "Invalid image for {}: {}", id, err);
+            None
+        }
+    }
+}
+
+/// Sends queued writes to the device, retrying a few times before giving up
+async fn flush(device: &Device) -> Result<(), MirajazzError> {
+    let mut attempt = 1;
+
+    loop {
+        match device.flush().await {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt < FLUSH_ATTEMPTS => {
+                log::warn!(
+                    "Flush failed (attempt {}/{}), retrying: {}",
+                    attempt,
+                    FLUSH_ATTEMPTS,
+                    err
+                );
+
+                attempt += 1;
+                sleep(FLUSH_RETRY_DELAY).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Writes an image to a button and keeps track of it in the panel state.
+///
+/// Starts with the candidate format that worked last for this kind of button, falling back
+/// to the next ones if the device library rejects it, and remembers the one that worked.
+/// Before moving on to another format, an image in an unusual color type is converted to RGB.
+/// Images are downscaled to the button size beforehand, so running out of formats is rare.
+/// If it happens, the error is logged with what was sent instead of being passed on, and
+/// the button is either left as it was or cleared, depending on the config.
+async fn write_button_image(
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    id: &str,
+    position: u8,
+    formats: &[ImageFormat],
+    image: DynamicImage,
+) -> Result<(), MirajazzError> {
+    let (width, height) = (image.width(), image.height());
+    let color = image.color();
+    let mut image = image;
+
+    // Touch zones are driven as buttons at the lowest hardware indices
+    let touch_zone = position < TOUCH_ZONE_COUNT as u8;
+    let start = if touch_zone {
+        state.touch_zone_format
+    } else {
+        state.button_format
+    }
+    .min(formats.len() - 1);
+    let expected = formats[start].size;
+
+    // Forget the position until the write went through, so a failed one gets redrawn
+    let shown = state.images.remove(&position);
+
+    let transition = !CONFIG.image_transition.is_zero();
+    let cached = transition.then(|| image.clone());
+
+    // Without an image on the button, there's nothing to fade from
+    if let Some(previous) = state.frames.get(&position).filter(|_| transition && shown) {
+        // A frame that can't be rendered only costs the transition, the image is still written
+        match fade(device, pending, position, &formats[start], previous, &image).await {
+            Err(MirajazzError::ImageError(err)) => log::debug!(
+                "Skipping transition on button {} of {}: {}",
+                position,
+                id,
+                err
+            ),
+            result => result?,
+        }
+    }
+
+    let mut chosen = start;
+
+    let result = loop {
+        match device
+            .set_button_image(position, formats[chosen], image.clone())
+            .await
+        {
+            // Unusual color types can trip up the encoder, plain RGB is the safest bet
+            Err(MirajazzError::ImageError(err)) if !matches!(image, DynamicImage::ImageRgb8(_)) => {
+                log::debug!(
+                    "Button {} of {} could not take a {:?} image, converting it to RGB: {}",
+                    position,
+                    id,
+                    image.color(),
+                    err
+                );
+                image = DynamicImage::ImageRgb8(image.to_rgb8());
+            }
+            Err(MirajazzError::ImageError(err)) if chosen + 1 < formats.len() => {
+                log::debug!(
+                    "Button {} of {} rejected {:?}, trying the next format: {}",
+                    position,
+                    id,
+                    formats[chosen],
+                    err
+                );
+                chosen += 1;
+            }
+            result => break result,
+        }
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(MirajazzError::ImageError(err)) => {
+            log_limited!(
+                Level::Warn,
+                id,
+                "Could not render {}x{} {:?} image on button {} of {} (expected {}x{}): {}",
+                width,
+                height,
+                color,
+                position,
+                id,
+                expected.0,
+                expected.1,
+                err
+            );
+
+            if CONFIG.clear_on_image_error {
+                // A blank button makes the failure visible, unlike an outdated image
+                device.clear_button_image(position).await?;
+                flush(device).await?;
+            } else if shown {
+                state.images.insert(position);
+            }
+
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    }
+
+    if chosen != start {
+        log::info!(
+            "Using image format {:?} for {} of {}",
+            formats[chosen],
+            if touch_zone { "touch zones" } else { "buttons" },
+            id
+        );
+
+        if touch_zone {
+            state.touch_zone_format = chosen;
+        } else {
+            state.button_format = chosen;
+        }
+    }
+
+    flush(device).await?;
+    state.images.insert(position);
+
+    if let Some(image) = cached {
+        state.frames.insert(position, image);
+    }
+
+    Ok(())
+}
+
+/// Shows intermediate frames blending from the previous image of a button to the next one.
+///
+/// Stops early as soon as another message is waiting, so a newer image doesn't queue up
+/// behind a running transition. The caller writes the final image either way.
+async fn fade(
+    device: &Device,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    position: u8,
+    format: &ImageFormat,
+    from: &DynamicImage,
+    to: &DynamicImage,
+) -> Result<(), MirajazzError> {
+    let steps = (CONFIG.image_transition.as_millis() / TRANSITION_STEP.as_millis()) as u32;
+
+    for step in 1..steps {
+        if !pending.is_empty() {
+            log::debug!("Interrupting transition on button {}", position);
+            break;
+        }
+
+        let frame = blend(from, to, step as f32 / steps as f32);
+
+        device.set_button_image(position, *format, frame).await?;
+        flush(device).await?;
+        sleep(TRANSITION_STEP).await;
+    }
+
+    Ok(())
+}
+
+/// Writes a touch zone image to its section of a programmable touch strip
+async fn write_strip_image(
+    device: &Device,
+    state: &mut DeviceState,
+    zone: u8,
+    format: ImageFormat,
+    image: DynamicImage,
+) -> Result<(), MirajazzError> {
+    let (width, height) = format.size;
+
+    // Strips take raw pixels, so the image has to have the exact size of the zone
+    let image = image.resize_exact(width as u32, height as u32, FilterType::Triangle);
+    let image = apply_format_transforms(image, &format);
+    let rect = ImageRect::from_image(image)?;
+
+    state.images.remove(&zone);
+
+    device
+        .write_lcd(zone as u16 * width as u16, 0, &rect)
+        .await?;
+    flush(device).await?;
+    state.images.insert(zone);
+
+    Ok(())
+}
+
+/// Blanks a touch zone, whichever way its kind drives them
+async fn clear_touch_zone(
+    device: &Device,
+    state: &mut DeviceState,
+    zone: u8,
+) -> Result<(), MirajazzError> {
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
 
-/// Initializes a device and listens for events
-pub async fn device_task(candidate: CandidateDevice, token: CancellationToken) {
-    log::info!("Running device task for {:?}", candidate);
+    match kind.touch_zone_mode() {
+        TouchZoneMode::Buttons => device.clear_button_image(zone).await?,
+        TouchZoneMode::Strip => {
+            let format = kind.image_format_touchzone();
+            let blank = DynamicImage::new_rgb8(format.size.0 as u32, format.size.1 as u32);
 
-    // Wrap in a closure so we can use `?` operator
-    let device = async || -> Result<Device, MirajazzError> {
-        let device = connect(&candidate).await?;
+            write_strip_image(device, state, zone, format, blank).await?;
+        }
+    }
 
-        device.set_brightness(50).await?;
-        device.clear_all_button_images().await?;
-        device.flush().await?;
+    state.images.remove(&zone);
 
-        Ok(device)
-    }()
-    .await;
+    Ok(())
+}
 
-    let device: Device = match device {
-        Ok(device) => device,
-        Err(err) => {
-            handle_error(&candidate.id, err).await;
+/// Clears every button and touch zone, as configured with `AKP05_CLEAR_ALL`
+async fn clear_all_buttons(device: &Device) -> Result<(), MirajazzError> {
+    if CONFIG.clear_all == ClearAll::Command {
+        return device.clear_all_button_images().await;
+    }
 
-            log::error!(
-                "Had error during device init, finishing device task: {:?}",
-                candidate
-            );
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
 
-            return;
+    for index in 0..kind.key_count() as u8 {
+        if !kind.unused_indices().contains(&index) {
+            device.clear_button_image(index).await?;
         }
+    }
+
+    Ok(())
+}
+
+/// Blanks the panel or shows the disconnect images, as configured with `AKP05_ON_DISCONNECT`.
+///
+/// OpenDeck sends all images again once it's back, so nothing needs to be restored.
+async fn handle_connection_lost(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    let dir = match CONFIG.on_disconnect {
+        DisconnectAction::Exit | DisconnectAction::Hold => return Ok(()),
+        DisconnectAction::Blank => None,
+        // Without a folder, the panel is just blanked
+        DisconnectAction::Images => CONFIG.disconnect_images.as_deref(),
     };
 
-    log::info!("Registering device {}", candidate.id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound
-            .register_device(
-                candidate.id.clone(),
-                candidate.kind.human_name(),
-                ROW_COUNT as u8,
-                COL_COUNT as u8,
-                ENCODER_COUNT as u8,
-                DEVICE_TYPE,
-            )
-            .await
-            .unwrap();
+    log::info!("Clearing {} while OpenDeck is gone", id);
+
+    clear_all_buttons(device).await?;
+    flush(device).await?;
+    state.images.clear();
+
+    match dir {
+        Some(dir) => show_folder_images(id, device, state, pending, dir).await,
+        None => Ok(()),
     }
+}
 
-    DEVICES.write().await.insert(candidate.id.clone(), device);
+/// Draws the progress bars again, which OpenDeck doesn't know about and won't send when it's back
+async fn handle_reconnected(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    // Drawn on waking up
+    if state.idle && idle_replaces_images() {
+        return Ok(());
+    }
 
-    tokio::select! {
-        _ = device_events_task(&candidate) => {},
-        _ = token.cancelled() => {}
-    };
+    redraw_progress_bars(id, device, state, pending).await
+}
 
-    log::info!("Shutting down device {:?}", candidate);
+/// Whether going idle replaces the images on the panel, rather than only dimming it
+fn idle_replaces_images() -> bool {
+    CONFIG.idle_images.is_some()
+}
+
+/// Dims the panel once no input came in for `AKP05_IDLE_TIMEOUT_MS`, and replaces its images
+/// with the idle images if configured
+async fn enter_idle(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    let brightness = idle_brightness(state, CONFIG.idle_brightness);
 
-    if let Some(device) = DEVICES.read().await.get(&candidate.id) {
-        device.shutdown().await.ok();
+    log::info!("Dimming {} to {} while idle", id, brightness);
+    device.set_brightness(brightness).await?;
+
+    if !idle_replaces_images() {
+        return Ok(());
     }
 
-    log::info!("Device task finished for {:?}", candidate);
+    log::info!("Showing idle images on {}", id);
+
+    clear_all_buttons(device).await?;
+    flush(device).await?;
+    state.images.clear();
+
+    if let Some(dir) = &CONFIG.idle_images {
+        show_folder_images(id, device, state, pending, dir).await?;
+    }
+
+    redraw_progress_bars(id, device, state, pending).await
 }
 
-/// Handles errors, returning true if should continue, returning false if an error is fatal
-pub async fn handle_error(id: &String, err: MirajazzError) -> bool {
-    log::error!("Device {} error: {}", id, err);
+/// Marks the panel idle, returning the brightness to dim it to.
+///
+/// The brightness in the state stays, it's what the panel wakes up to.
+fn idle_brightness(state: &mut DeviceState, dimmed: u8) -> u8 {
+    state.idle = true;
 
-    // Some errors are not critical and can be ignored without sending disconnected event
-    if matches!(err, MirajazzError::ImageError(_) | MirajazzError::BadData) {
-        return true;
+    dimmed
+}
+
+/// Marks the panel awake, returning the brightness to restore
+fn wake_brightness(state: &mut DeviceState) -> u8 {
+    state.idle = false;
+
+    state.brightness
+}
+
+/// Takes a new brightness into the state, returning it if it's to be set on the device right
+/// away. While idle, it's only what the panel wakes up to.
+fn requested_brightness(state: &mut DeviceState, brightness: u8) -> Option<u8> {
+    state.brightness = brightness;
+
+    (!state.idle).then_some(brightness)
+}
+
+/// Brings back the brightness and images OpenDeck last sent, including those that came in
+/// while idle
+async fn handle_wake(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    if !state.idle {
+        return Ok(());
     }
 
-    log::info!("Deregistering device {}", id);
-    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-        outbound.deregister_device(id.clone()).await.unwrap();
+    log::info!("Waking up {}", id);
+
+    device.set_brightness(wake_brightness(state)).await?;
+
+    if !idle_replaces_images() {
+        return Ok(());
     }
 
-    log::info!("Cancelling tasks for device {}", id);
-    if let Some(token) = TOKENS.read().await.get(id) {
-        token.cancel();
+    clear_all_buttons(device).await?;
+    flush(device).await?;
+    state.images.clear();
+
+    for ((is_encoder, position), image) in state.snapshot.clone() {
+        let update = ImageUpdate {
+            device: id.to_string(),
+            is_encoder,
+            position: Some(position),
+            image: Some(image),
+        };
+
+        handle_set_image(device, state, pending, update).await?;
     }
 
-    log::info!("Removing device {} from the list", id);
-    DEVICES.write().await.remove(id);
+    redraw_progress_bars(id, device, state, pending).await
+}
 
-    log::info!("Finished clean-up for {}", id);
+/// Keeps track of the images OpenDeck has on the panel, the same way they are applied
+fn record_snapshot(snapshot: &mut HashMap<(bool, u8), String>, update: &ImageUpdate) {
+    match (update.position, &update.image) {
+        (Some(position), Some(image)) => {
+            snapshot.insert((update.is_encoder, position), image.clone());
+        }
+        (Some(position), None) => {
+            snapshot.remove(&(update.is_encoder, position));
+        }
+        // Clearing all buttons takes the touch zones along, like on the device
+        (None, None) if update.is_encoder => snapshot.retain(|(is_encoder, _), _| !is_encoder),
+        (None, None) => snapshot.clear(),
+        _ => {}
+    }
+}
 
-    false
+/// Draws every progress bar again at its last level, after the panel was cleared
+async fn redraw_progress_bars(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    for (encoder, level) in state.bars.clone() {
+        show_progress_bar(id, device, state, pending, encoder, level).await?;
+    }
+
+    Ok(())
 }
 
-pub async fn connect(candidate: &CandidateDevice) -> Result<Device, MirajazzError> {
-    let result = Device::connect(
-        &candidate.dev,
-        candidate.kind.protocol_version(),
-        KEY_COUNT,
-        ENCODER_COUNT,
-    )
-    .await;
+/// Draws the progress bar of an encoder on its touch zone
+async fn show_progress_bar(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    encoder: u8,
+    level: u8,
+) -> Result<(), MirajazzError> {
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
 
-    match result {
-        Ok(device) => Ok(device),
-        Err(e) => {
-            log::error!("Error while connecting to device: {e}");
+    let Some(zone) = touch_zone(&kind, encoder as usize) else {
+        log::warn!(
+            "Encoder {} of {} has no touch zone for a progress bar",
+            encoder,
+            id
+        );
+        return Ok(());
+    };
+    let zone = zone as u8;
 
-            Err(e)
+    state.bars.insert(encoder, level);
+
+    let formats = touch_zone_formats(device, id);
+    let image = progress_bar(level, formats[0].size);
+
+    match kind.touch_zone_mode() {
+        TouchZoneMode::Buttons => {
+            write_button_image(device, state, pending, id, zone, &formats, image).await
         }
+        TouchZoneMode::Strip => write_strip_image(device, state, zone, formats[0], image).await,
     }
 }
 
-/// Handles events from device to OpenDeck
-async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzError> {
-    log::info!("Connecting to {} for incoming events", candidate.id);
+/// Fills every button and touch zone with a test pattern.
+///
+/// Goes through the same position remap as OpenDeck images, so a pattern that looks right
+/// rules out both the hardware and the mapping when something is off with a profile.
+#[cfg(not(target_os = "windows"))]
+async fn show_test_pattern(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+) -> Result<(), MirajazzError> {
+    log::info!("Showing test pattern on {}", id);
 
-    let devices_lock = DEVICES.read().await;
-    let reader = match devices_lock.get(&candidate.id) {
-        Some(device) => device.get_reader(crate::inputs::process_input),
-        None => return Ok(()),
-    };
-    drop(devices_lock);
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
 
-    log::info!("Connected to {} for incoming events", candidate.id);
+    for position in 0..(kind.rows() * kind.cols()) as u8 {
+        let formats = button_formats(device, id);
+        let image = test_pattern(position, formats[0].size);
+        let index = hardware_button_index(oriented_position(&kind, position));
 
-    log::info!("Reader is ready for {}", candidate.id);
+        write_button_image(device, state, pending, id, index, &formats, image).await?;
+    }
 
-    loop {
-        log::info!("Reading updates...");
+    for encoder in 0..kind.encoder_count() as u8 {
+        let Some(zone) = touch_zone(&kind, encoder as usize) else {
+            continue;
+        };
+        let zone = zone as u8;
 
-        let updates = match reader.read(None).await {
-            Ok(updates) => updates,
-            Err(e) => {
-                if !handle_error(&candidate.id, e).await {
-                    break;
-                }
+        let formats = touch_zone_formats(device, id);
+        let image = test_pattern(encoder, formats[0].size);
 
-                continue;
+        match kind.touch_zone_mode() {
+            TouchZoneMode::Buttons => {
+                write_button_image(device, state, pending, id, zone, &formats, image).await?
             }
+            TouchZoneMode::Strip => {
+                write_strip_image(device, state, zone, formats[0], image).await?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Candidate image formats for the regular buttons of a device, with its size and mirroring
+/// overrides if configured
+fn button_formats(device: &Device, id: &str) -> Vec<ImageFormat> {
+    let mut formats = Kind::from_vid_pid(device.vid, device.pid)
+        .unwrap()
+        .image_formats();
+
+    // Panel revisions of the same kind may differ in button resolution
+    if let Some(size) = CONFIG.button_sizes.get(id) {
+        for format in &mut formats {
+            format.size = *size;
+        }
+    }
+
+    mirror_formats(formats, id)
+}
+
+/// Candidate image formats for the touch zones of a device, with its mirroring override if
+/// configured
+fn touch_zone_formats(device: &Device, id: &str) -> Vec<ImageFormat> {
+    let formats = Kind::from_vid_pid(device.vid, device.pid)
+        .unwrap()
+        .image_formats_touchzone();
+
+    mirror_formats(formats, id)
+}
+
+/// Replaces the mirroring of the kind with the one from `AKP05_IMAGE_MIRRORS`
+fn mirror_formats(mut formats: Vec<ImageFormat>, id: &str) -> Vec<ImageFormat> {
+    if let Some(mirroring) = CONFIG.image_mirrors.get(id) {
+        for format in &mut formats {
+            format.mirror = image_mirroring(*mirroring);
+        }
+    }
+
+    formats
+}
+
+/// Shows the images from a folder like `AKP05_STARTUP_IMAGES`, outside of OpenDeck.
+///
+/// Buttons are read from files named by their OpenDeck position, e.g. `0.png`, and touch
+/// zones from `encoder0.png` and so on. Positions without a file are left as they are.
+async fn show_folder_images(
+    id: &str,
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    dir: &Path,
+) -> Result<(), MirajazzError> {
+    log::info!("Showing images from {} on {}", dir.display(), id);
+
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
+
+    for position in 0..(kind.rows() * kind.cols()) as u8 {
+        let Some(image) = read_image_file(dir, &position.to_string()) else {
+            continue;
         };
 
-        for update in updates {
-            log::debug!("New update: {:#?}", update);
+        let formats = button_formats(device, id);
+        let image = preprocess(image, &formats[0]);
+        let index = hardware_button_index(oriented_position(&kind, position));
 
-            let id = candidate.id.clone();
+        write_button_image(device, state, pending, id, index, &formats, image).await?;
+    }
 
-            if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                match update {
-                    DeviceStateUpdate::ButtonDown(key) => outbound.key_down(id, key).await.unwrap(),
-                    DeviceStateUpdate::ButtonUp(key) => outbound.key_up(id, key).await.unwrap(),
-                    DeviceStateUpdate::EncoderDown(encoder) => {
-                        outbound.encoder_down(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderUp(encoder) => {
-                        outbound.encoder_up(id, encoder).await.unwrap();
-                    }
-                    DeviceStateUpdate::EncoderTwist(encoder, val) => {
-                        outbound
-                            .encoder_change(id, encoder, val as i16)
-                            .await
-                            .unwrap();
-                    }
-                }
+    for encoder in 0..kind.encoder_count() as u8 {
+        let Some(image) = read_image_file(dir, &format!("encoder{}", encoder)) else {
+            continue;
+        };
+
+        let Some(zone) = touch_zone(&kind, encoder as usize) else {
+            continue;
+        };
+        let zone = zone as u8;
+
+        let formats = touch_zone_formats(device, id);
+        let image = preprocess(image, &formats[0]);
+
+        match kind.touch_zone_mode() {
+            TouchZoneMode::Buttons => {
+                write_button_image(device, state, pending, id, zone, &formats, image).await?
+            }
+            TouchZoneMode::Strip => {
+                write_strip_image(device, state, zone, formats[0], image).await?
             }
         }
     }
@@ -177,103 +1984,167 @@ async fn device_events_task(candidate: &CandidateDevice) -> Result<(), MirajazzE
 }
 
 /// Handles image setting for buttons and encoder touch zones
-pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(), MirajazzError> {
+async fn handle_set_image(
+    device: &Device,
+    state: &mut DeviceState,
+    pending: &mpsc::Receiver<DeviceMessage>,
+    update: ImageUpdate,
+) -> Result<(), MirajazzError> {
+    if !CONFIG.idle_timeout.is_zero() && idle_replaces_images() {
+        record_snapshot(&mut state.snapshot, &update);
+    }
+
+    // Drawn on waking up, from the snapshot
+    if state.idle && idle_replaces_images() {
+        return Ok(());
+    }
+
+    let kind = Kind::from_vid_pid(device.vid, device.pid).unwrap();
+
     // Check if this is an encoder touch zone or a regular button
-    let is_encoder = evt.controller.as_deref() == Some("Encoder");
+    let is_encoder = update.is_encoder;
+
+    // Touch zones with a progress bar are drawn by the plugin alone
+    let has_bar = |encoder: u8| CONFIG.progress_bars.contains(&encoder);
+
+    if is_encoder && update.position.is_some_and(has_bar) {
+        log::debug!(
+            "Ignoring image for encoder {:?}, it shows a progress bar",
+            update.position
+        );
+        return Ok(());
+    }
 
     if is_encoder {
         // Handle encoder touch zone rendering
         // Hardware has 4 discrete wide LCD buttons (indices 0-3), not a programmable strip
         // Map encoder positions directly to these wide buttons
-        match (evt.position, evt.image) {
+        let touch_zone = match update.position {
+            Some(encoder) => match touch_zone(&kind, encoder as usize) {
+                Some(zone) => Some(zone as u8),
+                // Would otherwise land on whatever button has the index
+                None => {
+                    log_limited!(
+                        Level::Warn,
+                        update.device,
+                        "Ignoring image for encoder {} of {}, it has no touch zone",
+                        encoder,
+                        update.device
+                    );
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+
+        match (touch_zone, update.image) {
             (Some(encoder_index), Some(image)) => {
-                log::info!("Setting touch zone image for encoder {} (button index {})", encoder_index, encoder_index);
+                log::info!(
+                    "Setting touch zone image for encoder {:?} (button index {})",
+                    update.position,
+                    encoder_index
+                );
 
-                // OpenDeck sends image as a data URL
-                let url = DataUrl::process(image.as_str()).unwrap();
-                let (body, _fragment) = url.decode_to_vec().unwrap();
+                // Invalid images are not fatal, the previous image is kept
+                let Some(image_loaded) = load_image(&update.device, &image) else {
+                    return Ok(());
+                };
 
-                // Allow only image/jpeg mime type
-                if url.mime_type().subtype != "jpeg" {
-                    log::error!("Incorrect mime type: {}", url.mime_type());
-                    return Ok(()); // Not fatal, just log it
-                }
+                let image_formats = touch_zone_formats(device, &update.device);
+                let image_loaded = preprocess(image_loaded, &image_formats[0]);
 
-                let image_loaded = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+                if kind.touch_zone_mode() == TouchZoneMode::Strip {
+                    write_strip_image(device, state, encoder_index, image_formats[0], image_loaded)
+                        .await?;
+                    return Ok(());
+                }
 
                 // Hardware uses button index positioning (discrete LCD buttons, not programmable strip)
                 // Tested: write_lcd() is accepted but silently ignored - hardware doesn't support pixel positioning
-                let image_format = Kind::from_vid_pid(device.vid, device.pid)
-                    .unwrap()
-                    .image_format_touchzone();
-
-                device.set_button_image(encoder_index, image_format, image_loaded).await?;
-                device.flush().await?;
+                write_button_image(
+                    device,
+                    state,
+                    pending,
+                    &update.device,
+                    encoder_index,
+                    &image_formats,
+                    image_loaded,
+                )
+                .await?;
             }
             (Some(encoder_index), None) => {
-                log::info!("Clearing touch zone for encoder {} (button index {})", encoder_index, encoder_index);
+                log::info!(
+                    "Clearing touch zone for encoder {:?} (button index {})",
+                    update.position,
+                    encoder_index
+                );
 
                 // Clear the wide button at this encoder index
-                device.clear_button_image(encoder_index).await?;
-                device.flush().await?;
+                clear_touch_zone(device, state, encoder_index).await?;
+                flush(device).await?;
             }
             (None, None) => {
                 log::info!("Clearing all touch zones (buttons 0-3)");
 
                 // Clear the 4 wide touch zone buttons (indices 0-3)
-                for i in 0..4 {
-                    device.clear_button_image(i).await?;
+                for i in 0..kind.encoder_count() as u8 {
+                    if !zone_encoder(&kind, i as usize)
+                        .is_some_and(|encoder| has_bar(encoder as u8))
+                    {
+                        clear_touch_zone(device, state, i).await?;
+                    }
                 }
-                device.flush().await?;
+                flush(device).await?;
             }
             _ => {}
         }
     } else {
         // Handle regular button rendering (2x5 grid, positions 0-9)
         // Position correction needed: hardware rows are reversed from OpenDeck layout
-        // OpenDeck layout:    Hardware layout:
-        // [0] [1] [2] [3] [4]   [10] [11] [12] [13] [14]  <- Top row
-        // [5] [6] [7] [8] [9]   [5]  [6]  [7]  [8]  [9]  <- Bottom row
-
-        let corrected_pos = evt.position.map(|pos| {
-            match pos {
-                0..=4 => pos + 10,  // Top row: OpenDeck 0-4 → Hardware 10-14
-                5..=9 => pos,       // Bottom row: OpenDeck 5-9 → Hardware 5-9
-                _ => pos,           // Invalid, pass through
-            }
-        });
+        let corrected_pos = update
+            .position
+            .map(|position| hardware_button_index(oriented_position(&kind, position)));
 
-        match (corrected_pos, evt.image) {
+        match (corrected_pos, update.image) {
             (Some(position), Some(image)) => {
-                log::info!("Setting image for button {} (OpenDeck pos: {:?})", position, evt.position);
-
-                // OpenDeck sends image as a data URL
-                let url = DataUrl::process(image.as_str()).unwrap();
-                let (body, _fragment) = url.decode_to_vec().unwrap();
+                log::info!(
+                    "Setting image for button {} (OpenDeck pos: {:?})",
+                    position,
+                    update.position
+                );
 
-                // Allow only image/jpeg mime type
-                if url.mime_type().subtype != "jpeg" {
-                    log::error!("Incorrect mime type: {}", url.mime_type());
-                    return Ok(()); // Not fatal, just log it
-                }
-
-                let image = load_from_memory_with_format(body.as_slice(), image::ImageFormat::Jpeg)?;
+                // Invalid images are not fatal, the previous image is kept
+                let Some(image) = load_image(&update.device, &image) else {
+                    return Ok(());
+                };
 
-                let image_format = Kind::from_vid_pid(device.vid, device.pid)
-                    .unwrap()
-                    .image_format();
+                let image_formats = button_formats(device, &update.device);
+                let image = preprocess(image, &image_formats[0]);
 
-                device.set_button_image(position, image_format, image).await?;
-                device.flush().await?;
+                write_button_image(
+                    device,
+                    state,
+                    pending,
+                    &update.device,
+                    position,
+                    &image_formats,
+                    image,
+                )
+                .await?;
             }
             (Some(position), None) => {
                 device.clear_button_image(position).await?;
-                device.flush().await?;
+                flush(device).await?;
+                state.images.remove(&position);
             }
             (None, None) => {
                 // Clear all buttons (includes touch zone buttons 0-3 and regular buttons 5-14)
-                device.clear_all_button_images().await?;
-                device.flush().await?;
+                clear_all_buttons(device).await?;
+                flush(device).await?;
+                state.images.clear();
+
+                // OpenDeck doesn't send progress bars, so they'd stay blank
+                redraw_progress_bars(&update.device, device, state, pending).await?;
             }
             _ => {}
         }
@@ -281,3 +2152,282 @@ pub async fn handle_set_image(device: &Device, evt: SetImageEvent) -> Result<(),
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::reserve_device;
+
+    #[test]
+    fn init_retry_delay_doubles_up_to_max() {
+        let delays: Vec<Duration> = (0..8).map(init_retry_delay).collect();
+
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000].map(Duration::from_millis)
+        );
+        assert_eq!(init_retry_delay(u32::MAX), INIT_RETRY_DELAY_MAX);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn init_is_retried_until_it_works() {
+        let token = CancellationToken::new();
+        let start = Instant::now();
+        let mut attempts = 0;
+
+        let result = retry_init("test", &token, async || {
+            attempts += 1;
+
+            match attempts {
+                1 | 2 => Err(MirajazzError::BadData),
+                _ => Ok(attempts),
+            }
+        })
+        .await;
+
+        assert_eq!(result, Some(3));
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_ends_init_retries_right_away() {
+        let token = CancellationToken::new();
+        let start = Instant::now();
+
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(2)).await;
+            canceller.cancel();
+        });
+
+        let result: Option<()> =
+            retry_init("test", &token, async || Err(MirajazzError::BadData)).await;
+
+        // Cancelled during the wait after the second attempt, which would last until 3.5s
+        assert_eq!(result, None);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_ends_a_hanging_init_attempt() {
+        let token = CancellationToken::new();
+        token.cancel();
+
+        let result: Option<()> = retry_init("test", &token, async || {
+            std::future::pending::<Result<(), MirajazzError>>().await
+        })
+        .await;
+
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_dims_and_input_restores_brightness() {
+        let idle = IdleTimer::new(Duration::from_secs(30));
+        let mut state = DeviceState::new(80);
+
+        sleep(Duration::from_secs(29)).await;
+        assert!(!idle.enter());
+        assert_eq!(idle.remaining(), Some(Duration::from_secs(1)));
+
+        sleep(Duration::from_secs(2)).await;
+        assert!(idle.enter());
+        assert_eq!(idle.remaining(), None);
+        assert_eq!(idle_brightness(&mut state, 10), 10);
+
+        // Changing the brightness while dimmed only sets what the panel wakes up to
+        assert_eq!(requested_brightness(&mut state, 60), None);
+        assert!(state.idle);
+        assert_eq!(state.brightness, 60);
+
+        assert!(idle.touch());
+        assert_eq!(wake_brightness(&mut state), 60);
+        assert!(!state.idle);
+        assert_eq!(idle.remaining(), Some(Duration::from_secs(30)));
+
+        assert_eq!(requested_brightness(&mut state, 70), Some(70));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn input_keeps_the_panel_awake() {
+        let idle = IdleTimer::new(Duration::from_secs(30));
+
+        sleep(Duration::from_secs(20)).await;
+        assert!(!idle.touch());
+
+        sleep(Duration::from_secs(20)).await;
+        assert!(!idle.enter());
+
+        sleep(Duration::from_secs(10)).await;
+        assert!(idle.enter());
+    }
+
+    fn touch_zone_image(encoder: u8, image: &str) -> ImageUpdate {
+        ImageUpdate {
+            device: "test".to_string(),
+            is_encoder: true,
+            position: Some(encoder),
+            image: Some(image.to_string()),
+        }
+    }
+
+    /// Reserves the id and registers a handle for it, like a device task once it's running
+    async fn start_device(id: &str) -> (CancellationToken, mpsc::Receiver<DeviceMessage>) {
+        let token = reserve_device(id).await.unwrap();
+        let (sender, receiver) = mpsc::channel(4);
+        let handle = DeviceHandle {
+            kind: Kind::N4,
+            name: None,
+            sender,
+        };
+
+        DEVICES.write().await.insert(id.to_string(), handle);
+
+        (token, receiver)
+    }
+
+    #[tokio::test]
+    async fn devices_of_the_same_kind_are_kept_apart() {
+        let first = "n4-TESTPEERFIRST".to_string();
+        let second = "n4-TESTPEERSECOND".to_string();
+
+        let (first_token, mut first_receiver) = start_device(&first).await;
+        let (second_token, mut second_receiver) = start_device(&second).await;
+
+        let mut update = touch_zone_image(0, "first");
+        update.device = first.clone();
+        send_to_device(&first, DeviceMessage::SetImage(update)).await;
+
+        match first_receiver.try_recv() {
+            Ok(DeviceMessage::SetImage(update)) => {
+                assert_eq!(update.image.as_deref(), Some("first"))
+            }
+            other => panic!("expected an image, got {:?}", other),
+        }
+        assert!(second_receiver.try_recv().is_err());
+
+        // Unplugging the first one leaves the second one running
+        cancel_device(&first, true).await;
+        release_device(&first, &first_token).await;
+
+        assert!(first_token.is_cancelled());
+        assert!(!second_token.is_cancelled());
+
+        let tokens = TOKENS.read().await;
+        assert!(!tokens.contains_key(&first));
+        assert!(!tokens[&second].is_cancelled());
+        drop(tokens);
+
+        let devices = DEVICES.read().await;
+        assert!(!devices.contains_key(&first));
+        assert!(devices.contains_key(&second));
+        drop(devices);
+
+        send_to_device(&second, DeviceMessage::SetBrightness(40)).await;
+        assert!(matches!(
+            second_receiver.try_recv(),
+            Ok(DeviceMessage::SetBrightness(40))
+        ));
+
+        release_device(&second, &second_token).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_back_images_for_a_zone_apply_the_last_one() {
+        let activity = TwistActivity::new(Duration::from_millis(200));
+        let mut held = HashMap::new();
+
+        activity.record(0);
+
+        assert!(hold_back(&activity, &mut held, touch_zone_image(0, "first")).is_none());
+        assert!(hold_back(&activity, &mut held, touch_zone_image(0, "second")).is_none());
+
+        // Other zones aren't held back
+        let other = hold_back(&activity, &mut held, touch_zone_image(1, "other"));
+        assert_eq!(other.unwrap().image.as_deref(), Some("other"));
+
+        assert!(take_rested(&activity, &mut held).is_none());
+
+        sleep(Duration::from_millis(200)).await;
+
+        let applied = take_rested(&activity, &mut held).unwrap();
+        assert_eq!(applied.position, Some(0));
+        assert_eq!(applied.image.as_deref(), Some("second"));
+        assert!(take_rested(&activity, &mut held).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn images_queue_behind_a_held_back_one() {
+        let activity = TwistActivity::new(Duration::from_millis(200));
+        let mut held = HashMap::new();
+
+        activity.record(0);
+        assert!(hold_back(&activity, &mut held, touch_zone_image(0, "first")).is_none());
+
+        // Rested, but an image is still waiting, so this one must not overtake it
+        sleep(Duration::from_millis(200)).await;
+        assert!(hold_back(&activity, &mut held, touch_zone_image(0, "second")).is_none());
+
+        let applied = take_rested(&activity, &mut held).unwrap();
+        assert_eq!(applied.image.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn invalid_base64_image_is_rejected() {
+        assert!(load_image("test", "data:image/jpeg;base64,!!!").is_none());
+    }
+
+    #[test]
+    fn truncated_jpeg_is_rejected() {
+        // Starts like a JPEG, but ends before its first segment
+        assert!(load_image("test", "data:image/jpeg;base64,/9j/4AAQ").is_none());
+    }
+
+    #[test]
+    fn unknown_mime_type_is_rejected() {
+        assert!(load_image("test", "data:text/plain;base64,aGVsbG8=").is_none());
+        assert!(load_image("test", "data:image/webp;base64,aGVsbG8=").is_none());
+    }
+
+    #[test]
+    fn brightness_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("opendeck-akp05-{}", std::process::id()));
+        let path = dir.join("brightness.json");
+
+        let brightness = HashMap::from([("n4-ABC".to_string(), 40), ("n4-DEF".to_string(), 0)]);
+
+        save_brightness_to(&path, &brightness).unwrap();
+        assert_eq!(load_brightness_from(&path), brightness);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn brightness_file_is_clamped_on_load() {
+        let dir = std::env::temp_dir().join(format!("opendeck-akp05-{}-clamp", std::process::id()));
+        let path = dir.join("brightness.json");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, r#"{"n4-ABC": 250}"#).unwrap();
+
+        assert_eq!(load_brightness_from(&path)["n4-ABC"], 100);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_brightness_file_is_empty() {
+        let path = std::env::temp_dir().join("opendeck-akp05-missing/brightness.json");
+
+        assert!(load_brightness_from(&path).is_empty());
+    }
+
+    #[test]
+    fn brightness_is_clamped_to_100() {
+        assert_eq!(clamp_brightness(0), 0);
+        assert_eq!(clamp_brightness(100), 100);
+        assert_eq!(clamp_brightness(101), 100);
+        assert_eq!(clamp_brightness(150), 100);
+        assert_eq!(clamp_brightness(255), 100);
+    }
+}
@@ -0,0 +1,434 @@
+use std::path::Path;
+
+use image::{
+    DynamicImage, GenericImageView, Rgba, RgbaImage,
+    imageops::{self, FilterType},
+    load_from_memory_with_format,
+};
+use mirajazz::{
+    error::MirajazzError,
+    types::{ImageFormat, ImageMirroring, ImageRotation},
+};
+
+use crate::config::{BarOrientation, CONFIG};
+
+/// Hints tooling can pass in the data URL fragment, e.g. `data:image/jpeg;base64,...#format=jpeg`
+#[derive(Debug, Default)]
+pub struct ImageHints {
+    /// Image format as a mime subtype, taking precedence over the data URL's mime type
+    pub format: Option<String>,
+}
+
+impl ImageHints {
+    /// Parses `key=value` pairs separated by `&`, ignoring the ones that aren't known
+    pub fn parse(fragment: &str) -> Self {
+        let mut hints = Self::default();
+
+        for pair in fragment.split('&').filter(|pair| !pair.is_empty()) {
+            match pair.split_once('=') {
+                Some(("format", value)) => {
+                    let format = value.to_ascii_lowercase();
+                    hints.format = Some(if format == "jpg" {
+                        "jpeg".into()
+                    } else {
+                        format
+                    });
+                }
+                _ => log::debug!("Ignoring unknown image hint {:?}", pair),
+            }
+        }
+
+        hints
+    }
+}
+
+/// Decodes a JPEG payload, rejecting data that was cut off in transit.
+///
+/// The `image` crate happily decodes a truncated JPEG into a partially garbage image, so
+/// the payload has to start with the SOI marker and end with the EOI marker.
+pub fn decode_jpeg(body: &[u8]) -> Result<DynamicImage, MirajazzError> {
+    // Some encoders pad the payload with zeroes after the EOI marker
+    let end = body
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |i| i + 1);
+    let data = &body[..end];
+
+    if !data.starts_with(&[0xFF, 0xD8]) || !data.ends_with(&[0xFF, 0xD9]) {
+        log::debug!("Rejecting truncated JPEG of {} bytes", body.len());
+        return Err(MirajazzError::BadData);
+    }
+
+    Ok(load_from_memory_with_format(
+        data,
+        image::ImageFormat::Jpeg,
+    )?)
+}
+
+/// Maps a mime subtype other than JPEG to a format that can be decoded and sent as JPEG
+pub fn transcodable_format(subtype: &str) -> Option<image::ImageFormat> {
+    match subtype {
+        "png" => Some(image::ImageFormat::Png),
+        "bmp" | "x-bmp" => Some(image::ImageFormat::Bmp),
+        _ => None,
+    }
+}
+
+/// Guesses the mime subtype of a payload from its magic bytes, for when its mime type says
+/// nothing useful, e.g. `application/octet-stream`. Only formats that can be decoded count
+pub fn sniff_format(body: &[u8]) -> Option<&'static str> {
+    match image::guess_format(body).ok()? {
+        image::ImageFormat::Jpeg => Some("jpeg"),
+        image::ImageFormat::Png => Some("png"),
+        image::ImageFormat::Bmp => Some("bmp"),
+        _ => None,
+    }
+}
+
+/// Decodes a payload in a format found with `transcodable_format`
+pub fn decode_other(
+    body: &[u8],
+    format: image::ImageFormat,
+) -> Result<DynamicImage, MirajazzError> {
+    Ok(load_from_memory_with_format(body, format)?)
+}
+
+/// Reads `{name}.jpg`, `.jpeg`, `.png` or `.bmp` from a folder, if there's one.
+///
+/// A missing file is expected, only files that can't be read are logged as warnings.
+pub fn read_image_file(dir: &Path, name: &str) -> Option<DynamicImage> {
+    let path = ["jpg", "jpeg", "png", "bmp"]
+        .iter()
+        .map(|extension| dir.join(format!("{}.{}", name, extension)))
+        .find(|path| path.is_file())?;
+
+    match image::open(&path) {
+        Ok(image) => Some(image),
+        Err(err) => {
+            log::warn!("Could not read image {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Optional post-decode pipeline applied before an image is sent to the device.
+///
+/// Steps run in order: flattening transparency, center-crop, padding, rounded corners,
+/// downscaling. Crop, padding and corners are disabled by default. Upscaling and encoding
+/// to the device format is still done by `mirajazz` afterwards.
+pub fn preprocess(image: DynamicImage, format: &ImageFormat) -> DynamicImage {
+    let mut image = image;
+
+    if image.color().has_alpha() {
+        image = flatten(image);
+    }
+
+    if CONFIG.image_crop {
+        image = center_crop(image, format.size);
+    }
+
+    if CONFIG.image_padding > 0 {
+        image = pad(image, CONFIG.image_padding);
+    }
+
+    if CONFIG.image_corner_radius > 0 {
+        image = round_corners(image, CONFIG.image_corner_radius);
+    }
+
+    if let Some(filter) = CONFIG.image_downscale_filter {
+        image = downscale(image, format.size, filter);
+    }
+
+    image
+}
+
+/// Rotates and mirrors an image the way `mirajazz` does for buttons.
+///
+/// Needed for raw writes like touch strips, where the device library sends pixels as-is.
+pub fn apply_format_transforms(image: DynamicImage, format: &ImageFormat) -> DynamicImage {
+    let image = match format.rotation {
+        ImageRotation::Rot0 => image,
+        ImageRotation::Rot90 => image.rotate90(),
+        ImageRotation::Rot180 => image.rotate180(),
+        ImageRotation::Rot270 => image.rotate270(),
+    };
+
+    match format.mirror {
+        ImageMirroring::None => image,
+        ImageMirroring::X => image.fliph(),
+        ImageMirroring::Y => image.flipv(),
+        ImageMirroring::Both => image.fliph().flipv(),
+    }
+}
+
+/// Mixes two images, `amount` going from 0 for just `from` to 1 for just `to`.
+///
+/// `from` is scaled to the size of `to` if they differ, e.g. after a button size change.
+pub fn blend(from: &DynamicImage, to: &DynamicImage, amount: f32) -> DynamicImage {
+    let to = to.to_rgba8();
+    let mut canvas = if from.dimensions() == to.dimensions() {
+        from.to_rgba8()
+    } else {
+        from.resize_exact(to.width(), to.height(), FilterType::Triangle)
+            .to_rgba8()
+    };
+
+    for (pixel, target) in canvas.pixels_mut().zip(to.pixels()) {
+        for (channel, target) in pixel.0.iter_mut().zip(target.0) {
+            let (a, b) = (*channel as f32, target as f32);
+            *channel = (a + (b - a) * amount).round() as u8;
+        }
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Solid color image with a white mark in the top left corner, to check a button works
+/// and is oriented correctly
+#[cfg(not(target_os = "windows"))]
+pub fn test_pattern(index: u8, size: (usize, usize)) -> DynamicImage {
+    const COLORS: [[u8; 3]; 7] = [
+        [220, 50, 50],
+        [50, 180, 50],
+        [50, 90, 220],
+        [230, 200, 40],
+        [200, 60, 200],
+        [40, 200, 200],
+        [240, 130, 30],
+    ];
+
+    let (width, height) = (size.0 as u32, size.1 as u32);
+    let [r, g, b] = COLORS[index as usize % COLORS.len()];
+    let mark = width.min(height) / 4;
+
+    let canvas = RgbaImage::from_fn(width, height, |x, y| {
+        if x < mark && y < mark {
+            Rgba([255, 255, 255, 255])
+        } else {
+            Rgba([r, g, b, 255])
+        }
+    });
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Bar filled to `level` percent in the progress bar color, on the background color
+pub fn progress_bar(level: u8, size: (usize, usize)) -> DynamicImage {
+    let (width, height) = (size.0 as u32, size.1 as u32);
+    let [r, g, b] = CONFIG.progress_bar_color.0;
+    let level = level.min(100) as u32;
+
+    let canvas = RgbaImage::from_fn(width, height, |x, y| {
+        let filled = match CONFIG.progress_bar_orientation {
+            BarOrientation::Horizontal => x < width * level / 100,
+            BarOrientation::Vertical => height - y <= height * level / 100,
+        };
+
+        if filled {
+            Rgba([r, g, b, 255])
+        } else {
+            background()
+        }
+    });
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Resizes images larger than the button with a high quality filter, keeping their aspect ratio.
+///
+/// OpenDeck may send images rendered for HiDPI screens, and `mirajazz` would otherwise
+/// scale them down with its own, faster filter. The result is letterboxed to the exact
+/// button size, so `mirajazz` doesn't stretch it a second time.
+fn downscale(image: DynamicImage, size: (usize, usize), filter: FilterType) -> DynamicImage {
+    let (width, height) = (size.0 as u32, size.1 as u32);
+
+    if image.width() <= width && image.height() <= height {
+        return image;
+    }
+
+    letterbox(image.resize(width, height, filter), width, height)
+}
+
+/// Centers the image on the background color at the given size
+fn letterbox(image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    if image.dimensions() == (width, height) {
+        return image;
+    }
+
+    let mut canvas = RgbaImage::from_pixel(width, height, background());
+    let x = (width.saturating_sub(image.width()) / 2) as i64;
+    let y = (height.saturating_sub(image.height()) / 2) as i64;
+
+    imageops::overlay(&mut canvas, &image.to_rgba8(), x, y);
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+}
+
+fn background() -> Rgba<u8> {
+    let [r, g, b] = CONFIG.image_background.0;
+
+    Rgba([r, g, b, 255])
+}
+
+/// Composites the image onto the background color.
+///
+/// JPEG has no alpha channel, so transparent areas would otherwise end up as whatever color
+/// values the encoder left under them.
+fn flatten(image: DynamicImage) -> DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(image.width(), image.height(), background());
+
+    imageops::overlay(&mut canvas, &image.to_rgba8(), 0, 0);
+
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+}
+
+/// Crops the largest centered area matching the aspect ratio of `size`
+fn center_crop(image: DynamicImage, size: (usize, usize)) -> DynamicImage {
+    let (width, height) = (image.width() as u64, image.height() as u64);
+    let (target_w, target_h) = (size.0 as u64, size.1 as u64);
+
+    if width == 0 || height == 0 || target_w == 0 || target_h == 0 {
+        return image;
+    }
+
+    // Compare width / height against target_w / target_h without floats
+    let (crop_w, crop_h) = if width * target_h > height * target_w {
+        (height * target_w / target_h, height)
+    } else {
+        (width, width * target_h / target_w)
+    };
+
+    if crop_w == width && crop_h == height {
+        return image;
+    }
+
+    image.crop_imm(
+        ((width - crop_w) / 2) as u32,
+        ((height - crop_h) / 2) as u32,
+        crop_w as u32,
+        crop_h as u32,
+    )
+}
+
+/// Surrounds the image with a uniform border of the background color
+fn pad(image: DynamicImage, percent: u32) -> DynamicImage {
+    let padding = image.width().min(image.height()) * percent / 100;
+
+    if padding == 0 {
+        return image;
+    }
+
+    let mut canvas = RgbaImage::from_pixel(
+        image.width() + padding * 2,
+        image.height() + padding * 2,
+        background(),
+    );
+
+    imageops::overlay(
+        &mut canvas,
+        &image.to_rgba8(),
+        padding as i64,
+        padding as i64,
+    );
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Paints everything outside of a rounded rectangle with the background color.
+///
+/// Devices only take JPEG, so corners are filled rather than made transparent.
+fn round_corners(image: DynamicImage, percent: u32) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let radius = width.min(height) * percent / 100;
+
+    if radius == 0 {
+        return image;
+    }
+
+    let mut canvas = image.to_rgba8();
+
+    let background = background();
+    let r = radius as i64;
+
+    for y in 0..height {
+        for x in 0..width {
+            // Distance from the nearest corner circle center, zero outside of the corner squares
+            let dx = (r - x as i64).max(x as i64 - (width as i64 - 1 - r)).max(0);
+            let dy = (r - y as i64)
+                .max(y as i64 - (height as i64 - 1 - r))
+                .max(0);
+
+            if dx * dx + dy * dy > r * r {
+                canvas.put_pixel(x, y, background);
+            }
+        }
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn jpeg() -> Vec<u8> {
+        let mut body = Vec::new();
+
+        DynamicImage::new_rgb8(16, 16)
+            .write_to(&mut Cursor::new(&mut body), image::ImageFormat::Jpeg)
+            .unwrap();
+
+        body
+    }
+
+    #[test]
+    fn complete_jpeg_is_decoded() {
+        let mut body = jpeg();
+        body.extend([0, 0, 0]);
+
+        assert_eq!(decode_jpeg(&body).unwrap().dimensions(), (16, 16));
+    }
+
+    #[test]
+    fn truncated_jpeg_is_rejected() {
+        let body = jpeg();
+
+        assert!(decode_jpeg(&body[..body.len() / 2]).is_err());
+        assert!(decode_jpeg(&body[..3]).is_err());
+        assert!(decode_jpeg(&[]).is_err());
+    }
+
+    #[test]
+    fn jpeg_markers_around_garbage_are_rejected() {
+        assert!(decode_jpeg(&[0xFF, 0xD8, 0x12, 0x34, 0xFF, 0xD9]).is_err());
+    }
+
+    #[test]
+    fn downscale_letterboxes_to_the_button_size() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([255, 0, 0])));
+
+        let image = downscale(image, (112, 112), FilterType::Lanczos3);
+
+        // Scaled to 112x56 and centered, with bands of the background above and below
+        assert_eq!(image.dimensions(), (112, 112));
+        assert_eq!(image.get_pixel(56, 0), background());
+        assert_eq!(image.get_pixel(56, 27), background());
+        assert_ne!(image.get_pixel(56, 28), background());
+        assert_ne!(image.get_pixel(56, 83), background());
+        assert_eq!(image.get_pixel(56, 84), background());
+        assert_eq!(image.get_pixel(56, 111), background());
+    }
+
+    #[test]
+    fn downscale_leaves_small_images_alone() {
+        let image = DynamicImage::new_rgb8(100, 50);
+
+        let image = downscale(image, (112, 112), FilterType::Lanczos3);
+
+        assert_eq!(image.dimensions(), (100, 50));
+    }
+}
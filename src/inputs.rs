@@ -1,6 +1,19 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
 use mirajazz::{error::MirajazzError, types::DeviceInput};
+use tokio::time::Instant;
 
-use crate::mappings::{ENCODER_COUNT, KEY_COUNT};
+use crate::{
+    config::{CONFIG, UnknownInputLog},
+    logging,
+    mappings::{ENCODER_COUNT, Kind, oriented_encoder, oriented_position, zone_encoder},
+};
 
 // TODO: These input mappings are placeholders and need to be verified with actual hardware
 // The actual input codes will need to be discovered by testing with the real device
@@ -8,65 +21,383 @@ use crate::mappings::{ENCODER_COUNT, KEY_COUNT};
 // Device layout: 10 regular buttons (2x5 grid) + 4 encoders with LCD touch zones
 // Touch zones are rendered via write_lcd and display encoder functions automatically by OpenDeck
 
-pub fn process_input(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+/// Encoder twist codes as `(code, encoder, direction)`, the only place they are listed
+const TWIST_CODES: [(u8, usize, i8); 8] = [
+    // Encoder 1
+    (0xA0, 0, -1),
+    (0xA1, 0, 1),
+    // Encoder 2
+    (0x50, 1, -1),
+    (0x51, 1, 1),
+    // Encoder 3
+    (0x90, 2, -1),
+    (0x91, 2, 1),
+    // Encoder 4
+    (0x70, 3, -1),
+    (0x71, 3, 1),
+];
+
+// A duplicate code would make one of its entries unreachable, so reject it at compile time
+const _: () = {
+    let mut i = 0;
+    while i < TWIST_CODES.len() {
+        assert!(TWIST_CODES[i].1 < ENCODER_COUNT);
+        assert!(TWIST_CODES[i].2 == 1 || TWIST_CODES[i].2 == -1);
+
+        let mut other = 0;
+        while other < i {
+            assert!(TWIST_CODES[other].0 != TWIST_CODES[i].0);
+            other += 1;
+        }
+
+        i += 1;
+    }
+};
+
+/// Looks up the encoder and direction of a twist code
+fn twist_code(input: u8) -> Option<(usize, i8)> {
+    TWIST_CODES
+        .iter()
+        .find(|(code, _, _)| *code == input)
+        .map(|(_, encoder, direction)| (*encoder, *direction))
+}
+
+/// Input processor for devices of the kind, with the layout of the kind.
+///
+/// The device library takes a plain function and doesn't tell which device the input comes
+/// from, so there's one for each kind.
+pub fn input_processor(kind: &Kind) -> fn(u8, u8) -> Result<DeviceInput, MirajazzError> {
+    match kind {
+        Kind::Akp05 => |input, state| process_input(&Kind::Akp05, input, state),
+        Kind::N4 => |input, state| process_input(&Kind::N4, input, state),
+    }
+}
+
+pub fn process_input(kind: &Kind, input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
     // Always emit a raw input line at debug for tracing
     log::debug!("Processing input: 0x{:02X}, state: {}", input, state);
 
     match input {
+        // Encoder rotation (4 encoders), checked first so no other arm can shadow a twist code
+        _ if twist_code(input).is_some() => read_encoder_value(kind, input, state),
+
         // Physical LCD buttons (10 total: 2x5 grid)
         // TODO: Verify actual input codes with hardware - these are placeholders
-        (0..=10) => read_button_press(input, state),
+        (0..=10) => read_button_press(kind, input, state),
 
         // Touch zone tap events - mapped to encoder button presses
         // TODO: Discover actual input codes - these are placeholder values
-        0x40..=0x43 => read_touch_tap(input, state),
+        0x40..=0x43 => read_touch_tap(kind, input, state),
 
         // Touchscreen swipe events (e.g., switch pages/profiles)
         // These are generated by the LCD touch strip
         // TODO: Verify actual input codes with hardware
         0x38 | 0x39 => read_touch_swipe(input, state),
 
-        // Encoder rotation (4 encoders)
-        0xA0 | 0xA1 | 0x50 | 0x51 | 0x90 | 0x91 | 0x70 | 0x71 => read_encoder_value(input),
-
         // Encoder press (4 encoders)
-        0x33..=0x37 => read_encoder_press(input, state),
+        0x33..=0x37 => read_encoder_press(kind, input, state),
 
         _ => {
-            // Unknown raw input; surface prominently to help mapping
-            log::info!("EVENT Unknown code=0x{:02X} state={}", input, state);
-            log::warn!("Unknown input code: 0x{:02X}, state: {}", input, state);
+            log_unknown_input(input, state);
             Err(MirajazzError::BadData)
         }
     }
 }
 
-fn read_button_states(states: &[u8]) -> Vec<bool> {
+/// Twists further apart than this start a new count for the encoder deadzone
+const DEADZONE_WINDOW: Duration = Duration::from_millis(300);
+
+/// Drops stray encoder detents, as configured with `AKP05_ENCODER_DEADZONE`.
+///
+/// An encoder has to be turned by its configured number of detents in quick succession
+/// before its twists go through, after that they do until it rests for `DEADZONE_WINDOW`.
+/// Each encoder counts on its own, so twisting one never lets a stray detent of another through.
+#[derive(Debug)]
+pub struct TwistFilter {
+    /// Detents required, per encoder
+    deadzones: [usize; ENCODER_COUNT],
+    /// Detents counted and time of the last twist, per encoder
+    encoders: [(usize, Option<Instant>); ENCODER_COUNT],
+}
+
+impl TwistFilter {
+    pub fn new(deadzones: [usize; ENCODER_COUNT]) -> Self {
+        Self {
+            deadzones,
+            encoders: [(0, None); ENCODER_COUNT],
+        }
+    }
+
+    /// Returns true if the twist should be passed on
+    pub fn allow(&mut self, encoder: u8, value: i8) -> bool {
+        let (Some(deadzone), Some((count, last))) = (
+            self.deadzones.get(encoder as usize),
+            self.encoders.get_mut(encoder as usize),
+        ) else {
+            return true;
+        };
+
+        if *deadzone <= 1 {
+            return true;
+        }
+
+        let now = Instant::now();
+
+        if last.is_none_or(|last| now.duration_since(last) > DEADZONE_WINDOW) {
+            *count = 0;
+        }
+
+        *count += value.unsigned_abs() as usize;
+        *last = Some(now);
+
+        if *count < *deadzone {
+            log::debug!(
+                "Ignoring twist of encoder {} in deadzone ({}/{})",
+                encoder,
+                count,
+                deadzone
+            );
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Synthesizes twists while an encoder is held down, as configured with `AKP05_HOLD_REPEAT_MS`.
+///
+/// The first twist comes one interval after the press, so a short press doesn't produce any.
+#[derive(Debug, Default)]
+pub struct HoldRepeat {
+    /// When the next twist is due, per held encoder
+    encoders: [Option<Instant>; ENCODER_COUNT],
+}
+
+impl HoldRepeat {
+    /// Starts repeating for the encoder, if enabled
+    pub fn press(&mut self, encoder: u8) {
+        let interval = CONFIG.hold_repeat;
+
+        if let Some(next) = self.encoders.get_mut(encoder as usize) {
+            *next = (!interval.is_zero()).then(|| Instant::now() + interval);
+        }
+    }
+
+    /// Stops repeating for the encoder
+    pub fn release(&mut self, encoder: u8) {
+        if let Some(next) = self.encoders.get_mut(encoder as usize) {
+            *next = None;
+        }
+    }
+
+    /// Returns how long until the next twist is due, if any encoder is held
+    pub fn timeout(&self) -> Option<Duration> {
+        let next = self.encoders.iter().flatten().min()?;
+
+        Some(next.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns the encoders whose twist is due, scheduling their next one
+    pub fn due(&mut self) -> Vec<u8> {
+        let now = Instant::now();
+        let mut due = vec![];
+
+        for (encoder, next) in self.encoders.iter_mut().enumerate() {
+            // Counting from now skips repeats that were missed instead of sending a burst
+            if next.is_some_and(|at| at <= now) {
+                *next = Some(now + CONFIG.hold_repeat);
+                due.push(encoder as u8);
+            }
+        }
+
+        due
+    }
+}
+
+/// Levels of the encoders with a progress bar, as configured with `AKP05_PROGRESS_BARS`
+#[derive(Debug, Default)]
+pub struct EncoderLevels {
+    levels: [u8; ENCODER_COUNT],
+}
+
+impl EncoderLevels {
+    /// Applies a twist, returning the new level if the encoder has a progress bar
+    pub fn apply(&mut self, encoder: u8, value: i8) -> Option<u8> {
+        if !CONFIG.progress_bars.contains(&encoder) {
+            return None;
+        }
+
+        let level = self.levels.get_mut(encoder as usize)?;
+        let change = value as i32 * CONFIG.progress_bar_step as i32;
+
+        *level = (*level as i32 + change).clamp(0, 100) as u8;
+
+        Some(*level)
+    }
+}
+
+/// When each encoder was last twisted, for holding back touch zone images with
+/// `AKP05_TWIST_IMAGE_HOLDOFF_MS`.
+///
+/// Shared between the event and message tasks of a device, which run side by side. Goes by the
+/// clock of the runtime, like `IdleTimer`.
+#[derive(Debug)]
+pub struct TwistActivity {
+    /// How long an encoder has to rest, never holding back if zero
+    holdoff: Duration,
+    encoders: Mutex<[Option<Instant>; ENCODER_COUNT]>,
+}
+
+impl TwistActivity {
+    pub fn new(holdoff: Duration) -> Self {
+        Self {
+            holdoff,
+            encoders: Mutex::new([None; ENCODER_COUNT]),
+        }
+    }
+
+    /// Notes that the encoder was just twisted
+    pub fn record(&self, encoder: u8) {
+        if self.holdoff.is_zero() {
+            return;
+        }
+
+        let mut encoders = self.encoders.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(last) = encoders.get_mut(encoder as usize) {
+            *last = Some(Instant::now());
+        }
+    }
+
+    /// Returns how long until the encoder has rested long enough, if it's still being twisted
+    pub fn remaining(&self, encoder: u8) -> Option<Duration> {
+        let encoders = self.encoders.lock().unwrap_or_else(|e| e.into_inner());
+        let last = (*encoders.get(encoder as usize)?)?;
+
+        self.holdoff
+            .checked_sub(last.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// When input last came in, for going idle with `AKP05_IDLE_TIMEOUT_MS`.
+///
+/// Shared between the event and message tasks of a device, like `TwistActivity`. Goes by the
+/// clock of the runtime, so the message task's timeout and this agree on when it's due.
+#[derive(Debug)]
+pub struct IdleTimer {
+    /// How long without input until the panel goes idle, never if zero
+    timeout: Duration,
+    last_input: Mutex<Instant>,
+    idle: AtomicBool,
+}
+
+impl IdleTimer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_input: Mutex::new(Instant::now()),
+            idle: AtomicBool::new(false),
+        }
+    }
+
+    /// Notes that input just came in, returning whether this woke the panel up
+    pub fn touch(&self) -> bool {
+        let mut last_input = self.last_input.lock().unwrap_or_else(|e| e.into_inner());
+        *last_input = Instant::now();
+
+        self.idle.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns how long until the panel goes idle, unless it already is or never does
+    pub fn remaining(&self) -> Option<Duration> {
+        let last_input = self.last_input.lock().unwrap_or_else(|e| e.into_inner());
+
+        if self.timeout.is_zero() || self.idle.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        Some(self.timeout.saturating_sub(last_input.elapsed()))
+    }
+
+    /// Marks the panel idle once the timeout ran out, returning whether it just went idle
+    pub fn enter(&self) -> bool {
+        // Holding the lock keeps input from coming in between the check and marking it idle
+        let last_input = self.last_input.lock().unwrap_or_else(|e| e.into_inner());
+
+        if self.timeout.is_zero()
+            || last_input.elapsed() < self.timeout
+            || self.idle.load(Ordering::Relaxed)
+        {
+            return false;
+        }
+
+        self.idle.store(true, Ordering::Relaxed);
+
+        true
+    }
+}
+
+fn log_unknown_input(input: u8, state: u8) {
+    let prominent = match CONFIG.unknown_input_log {
+        UnknownInputLog::Warn => true,
+        UnknownInputLog::Debug => false,
+        UnknownInputLog::OncePerMinute => {
+            let key = format!("unknown-input:{:02X}", input);
+
+            logging::allow(key, Duration::from_secs(60)).is_some()
+        }
+    };
+
+    if prominent {
+        // Unknown raw input; surface prominently to help mapping
+        log::info!("EVENT Unknown code=0x{:02X} state={}", input, state);
+        log::warn!("Unknown input code: 0x{:02X}, state: {}", input, state);
+    } else {
+        log::debug!("Unknown input code: 0x{:02X}, state: {}", input, state);
+    }
+}
+
+fn read_button_states(kind: &Kind, states: &[u8]) -> Vec<bool> {
     let mut bools = vec![];
 
-    for i in 0..KEY_COUNT {
+    for i in 0..kind.key_count() {
         bools.push(states[i + 1] != 0);
     }
 
     bools
 }
 
-fn read_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+/// Maps a button code to the OpenDeck grid position of the key reporting it
+///
+/// TODO: Map actual N4 input codes to button indices (1-10)
+/// This is a placeholder mapping that needs to be verified with real hardware
+const fn button_code_position(input: u8) -> Option<u8> {
+    match input {
+        // 10 buttons for N4 (2x5 grid), reported in OpenDeck order
+        1..=10 => Some(input - 1),
+        _ => None,
+    }
+}
+
+fn read_button_press(kind: &Kind, input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
     let mut button_states = vec![0x01];
-    button_states.extend(vec![0u8; KEY_COUNT + 1]);
+    button_states.extend(vec![0u8; kind.key_count() + 1]);
 
     if input == 0 {
         return Ok(DeviceInput::ButtonStateChange(read_button_states(
+            kind,
             &button_states,
         )));
     }
 
-    // TODO: Map actual N4 input codes to button indices (1-10)
-    // This is a placeholder mapping that needs to be verified with real hardware
-    let pressed_index: usize = match input {
-        (1..=10) => input as usize, // 10 buttons for N4 (2x5 grid)
-        _ => return Err(MirajazzError::BadData),
+    let grid = kind.rows() * kind.cols();
+
+    let Some(position) = button_code_position(input).filter(|position| (*position as usize) < grid)
+    else {
+        return Err(MirajazzError::BadData);
     };
+    let pressed_index = oriented_position(kind, position) as usize + 1;
 
     button_states[pressed_index] = state;
 
@@ -74,38 +405,57 @@ fn read_button_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError>
     log::info!("EVENT Button index={} state={}", pressed_index, state);
 
     Ok(DeviceInput::ButtonStateChange(read_button_states(
+        kind,
         &button_states,
     )))
 }
 
-fn read_encoder_value(input: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_values = vec![0i8; ENCODER_COUNT];
-
-    let (encoder, value): (usize, i8) = match input {
-        // Encoder 1
-        0xA0 => (0, -1),
-        0xA1 => (0, 1),
-        // Encoder 2
-        0x50 => (1, -1),
-        0x51 => (1, 1),
-        // Encoder 3
-        0x90 => (2, -1),
-        0x91 => (2, 1),
-        // Encoder 4
-        0x70 => (3, -1),
-        0x71 => (3, 1),
-        _ => return Err(MirajazzError::BadData),
+/// Stores the value of one encoder in a report, rejecting indices past the encoder count.
+///
+/// Code tables are shared between kinds, so one with fewer encoders could otherwise panic.
+fn set_encoder<T>(values: &mut [T], encoder: usize, value: T) -> Result<(), MirajazzError> {
+    let count = values.len();
+
+    match values.get_mut(encoder) {
+        Some(slot) => {
+            *slot = value;
+            Ok(())
+        }
+        None => {
+            log::warn!("Encoder {} is out of range, there are {}", encoder, count);
+            Err(MirajazzError::BadData)
+        }
+    }
+}
+
+/// Reads an encoder twist, where the code gives the encoder and direction.
+///
+/// Single-detent reports carry a state of 0 or 1. If the firmware coalesces several detents
+/// into one report, the state byte is taken as the step count, so a fast spin produces one
+/// event with the full delta instead of being collapsed to ±1.
+///
+/// Only states of 0 and 1 have been seen on real hardware so far. Reading larger values as
+/// the step count is an assumption, capped at `i8::MAX` so it can't overflow the delta.
+fn read_encoder_value(kind: &Kind, input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+    let mut encoder_values = vec![0i8; kind.encoder_count()];
+
+    let Some((encoder, direction)) = twist_code(input) else {
+        return Err(MirajazzError::BadData);
     };
 
-    encoder_values[encoder] = value;
+    let encoder = oriented_encoder(kind, encoder);
+    let steps = state.clamp(1, i8::MAX as u8) as i8;
+    let value = direction * steps;
+
+    set_encoder(&mut encoder_values, encoder, value)?;
 
     // Log recognized encoder twist
     log::info!("EVENT EncoderTwist encoder={} delta={}", encoder, value);
     Ok(DeviceInput::EncoderTwist(encoder_values))
 }
 
-fn read_encoder_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
-    let mut encoder_states = vec![false; ENCODER_COUNT];
+fn read_encoder_press(kind: &Kind, input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+    let mut encoder_states = vec![false; kind.encoder_count()];
 
     let encoder: usize = match input {
         0x37 => 0, // Encoder 1
@@ -114,15 +464,13 @@ fn read_encoder_press(input: u8, state: u8) -> Result<DeviceInput, MirajazzError
         0x36 => 3, // Encoder 4
         _ => return Err(MirajazzError::BadData),
     };
+    let encoder = oriented_encoder(kind, encoder);
 
-    encoder_states[encoder] = state != 0;
+    let pressed = state != 0;
+    set_encoder(&mut encoder_states, encoder, pressed)?;
 
     // Log recognized encoder press/release
-    log::info!(
-        "EVENT EncoderPress encoder={} state={}",
-        encoder,
-        encoder_states[encoder]
-    );
+    log::info!("EVENT EncoderPress encoder={} state={}", encoder, pressed);
     Ok(DeviceInput::EncoderStateChange(encoder_states))
 }
 
@@ -133,10 +481,16 @@ fn read_touch_swipe(input: u8, state: u8) -> Result<DeviceInput, MirajazzError>
     Ok(DeviceInput::NoData)
 }
 
-fn read_touch_tap(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+fn read_touch_tap(kind: &Kind, input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
+    // The tap codes are unverified, so they can be turned off if they misfire
+    if !CONFIG.touch_taps {
+        log::debug!("Ignoring touch tap 0x{:02X} state={}", input, state);
+        return Ok(DeviceInput::NoData);
+    }
+
     // Touch zone taps are treated as encoder button presses
     // Note: OpenDeck handles touch zone rendering automatically for device type 7
-    let mut encoder_states = vec![false; ENCODER_COUNT];
+    let mut encoder_states = vec![false; kind.encoder_count()];
 
     let encoder = match input {
         0x40 => 0, // Encoder 0 touch zone tap
@@ -145,11 +499,143 @@ fn read_touch_tap(input: u8, state: u8) -> Result<DeviceInput, MirajazzError> {
         0x43 => 3, // Encoder 3 touch zone tap
         _ => return Err(MirajazzError::BadData),
     };
+    let Some(encoder) = zone_encoder(kind, encoder) else {
+        return Err(MirajazzError::BadData);
+    };
 
     let active = state != 0;
-    encoder_states[encoder] = active;
+    set_encoder(&mut encoder_states, encoder, active)?;
 
     log::info!("EVENT TouchTap encoder={} active={}", encoder, active);
 
     Ok(DeviceInput::EncoderStateChange(encoder_states))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the positions reported as pressed
+    fn pressed(input: DeviceInput) -> Vec<usize> {
+        let DeviceInput::ButtonStateChange(states) = input else {
+            panic!("not a button state change");
+        };
+
+        states
+            .iter()
+            .enumerate()
+            .filter(|(_, pressed)| **pressed)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    #[test]
+    fn button_codes_report_their_grid_position() {
+        // Top row first, left to right, like the grid in OpenDeck
+        let codes = [
+            (0x01, 0),
+            (0x02, 1),
+            (0x03, 2),
+            (0x04, 3),
+            (0x05, 4),
+            (0x06, 5),
+            (0x07, 6),
+            (0x08, 7),
+            (0x09, 8),
+            (0x0A, 9),
+        ];
+
+        for (code, position) in codes {
+            let input = read_button_press(&Kind::N4, code, 1).unwrap();
+
+            assert_eq!(pressed(input), vec![position], "code 0x{:02X}", code);
+        }
+
+        assert!(read_button_press(&Kind::N4, 0x0B, 1).is_err());
+    }
+
+    #[test]
+    fn a_report_carries_a_single_button() {
+        // Holding one key while pressing another comes in as two reports of one key each
+        let first = read_button_press(&Kind::N4, 0x01, 1).unwrap();
+        let second = read_button_press(&Kind::N4, 0x03, 1).unwrap();
+
+        assert_eq!(pressed(first), vec![0]);
+        assert_eq!(pressed(second), vec![2]);
+
+        // A release code clears every key
+        assert!(pressed(read_button_press(&Kind::N4, 0x00, 0).unwrap()).is_empty());
+    }
+
+    /// Returns the twist reported for the encoder
+    fn twisted(input: DeviceInput, encoder: usize) -> i8 {
+        let DeviceInput::EncoderTwist(values) = input else {
+            panic!("not an encoder twist");
+        };
+
+        values[encoder]
+    }
+
+    #[test]
+    fn encoder_state_is_read_as_step_count() {
+        for (state, steps) in [(0, 1), (1, 1), (3, 3), (127, 127), (200, 127), (255, 127)] {
+            assert_eq!(
+                twisted(read_encoder_value(&Kind::N4, 0xA1, state).unwrap(), 0),
+                steps
+            );
+            assert_eq!(
+                twisted(read_encoder_value(&Kind::N4, 0xA0, state).unwrap(), 0),
+                -steps
+            );
+        }
+    }
+
+    #[test]
+    fn every_twist_code_is_read_as_a_twist() {
+        for (code, encoder, direction) in TWIST_CODES {
+            let input = process_input(&Kind::N4, code, 1).unwrap();
+
+            assert_eq!(twisted(input, encoder), direction, "code 0x{:02X}", code);
+        }
+    }
+
+    #[test]
+    fn deadzone_is_counted_per_encoder() {
+        let mut filter = TwistFilter::new([2; ENCODER_COUNT]);
+
+        // A detent of one encoder doesn't count toward the deadzone of another
+        assert!(!filter.allow(0, 1));
+        assert!(!filter.allow(1, 1));
+        assert!(!filter.allow(2, -1));
+
+        assert!(filter.allow(0, 1));
+        assert!(filter.allow(1, 1));
+        assert!(!filter.allow(3, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadzone_count_starts_over_after_a_rest() {
+        let mut filter = TwistFilter::new([2; ENCODER_COUNT]);
+
+        assert!(!filter.allow(0, 1));
+        tokio::time::sleep(DEADZONE_WINDOW).await;
+        assert!(filter.allow(0, 1));
+
+        tokio::time::sleep(DEADZONE_WINDOW + Duration::from_millis(1)).await;
+        assert!(!filter.allow(0, 1));
+    }
+
+    #[test]
+    fn deadzone_can_differ_per_encoder() {
+        let mut deadzones = [1; ENCODER_COUNT];
+        deadzones[0] = 3;
+
+        let mut filter = TwistFilter::new(deadzones);
+
+        assert!(filter.allow(1, 1));
+        assert!(!filter.allow(0, 1));
+        assert!(filter.allow(1, -1));
+        assert!(!filter.allow(0, 1));
+        assert!(filter.allow(0, 1));
+    }
+}
@@ -0,0 +1,76 @@
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Window used by `log_limited!` for messages in the hot paths
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Window {
+    started: Instant,
+    suppressed: u64,
+}
+
+static WINDOWS: LazyLock<Mutex<HashMap<String, Window>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Decides whether a message with the given key may be logged.
+///
+/// The first message in each interval goes through, the rest are counted. Returns `None`
+/// if the message should be dropped, otherwise the number of messages dropped since the
+/// last one that went through.
+pub fn allow(key: String, interval: Duration) -> Option<u64> {
+    let mut windows = WINDOWS.lock().unwrap_or_else(|e| e.into_inner());
+    let now = Instant::now();
+
+    match windows.get_mut(&key) {
+        Some(window) if now.duration_since(window.started) < interval => {
+            window.suppressed += 1;
+            None
+        }
+        Some(window) => {
+            let suppressed = window.suppressed;
+            *window = Window {
+                started: now,
+                suppressed: 0,
+            };
+            Some(suppressed)
+        }
+        None => {
+            windows.insert(
+                key,
+                Window {
+                    started: now,
+                    suppressed: 0,
+                },
+            );
+            Some(0)
+        }
+    }
+}
+
+/// Logs at most once per `LOG_INTERVAL` for each call site and key, e.g. a device id.
+///
+/// Repeated messages are collapsed, and the next one that goes through reports how many
+/// were dropped in between.
+macro_rules! log_limited {
+    ($level:expr, $key:expr, $($arg:tt)+) => {{
+        let key = format!("{}:{}:{}", file!(), line!(), $key);
+
+        if let Some(suppressed) = $crate::logging::allow(key, $crate::logging::LOG_INTERVAL) {
+            if suppressed > 0 {
+                log::log!(
+                    $level,
+                    "{} ({} more occurrences since the last report)",
+                    format_args!($($arg)+),
+                    suppressed
+                );
+            } else {
+                log::log!($level, $($arg)+);
+            }
+        }
+    }};
+}
+
+pub(crate) use log_limited;
@@ -1,25 +1,56 @@
-use device::{handle_error, handle_set_image};
-use mirajazz::device::Device;
+use config::{CONFIG, DisconnectAction};
+use device::{
+    DeviceHandle, DeviceMessage, clamp_brightness, notify_connection_lost, notify_reconnected,
+    reconcile_task, reregister_devices, send_to_device,
+};
 use openaction::*;
-use std::{collections::HashMap, process::exit, sync::LazyLock};
-use tokio::sync::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    process::exit,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{Mutex, RwLock},
+    time::{sleep, timeout},
+};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use watcher::watcher_task;
 
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use device::{log_device_states, show_test_patterns};
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{SignalKind, signal};
 
+mod config;
+#[cfg(not(target_os = "windows"))]
+mod control;
 mod device;
+mod images;
 mod inputs;
+mod logging;
 mod mappings;
+mod version;
 mod watcher;
 
-pub static DEVICES: LazyLock<RwLock<HashMap<String, Device>>> =
+pub static DEVICES: LazyLock<RwLock<HashMap<String, DeviceHandle>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TOKENS: LazyLock<RwLock<HashMap<String, CancellationToken>>> =
     LazyLock::new(|| RwLock::new(HashMap::new()));
 pub static TRACKER: LazyLock<Mutex<TaskTracker>> = LazyLock::new(|| Mutex::new(TaskTracker::new()));
 
+/// Set while connected to OpenDeck, so a lost connection is only handled once
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait for all tasks to finish on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay between attempts to reconnect to OpenDeck, see `AKP05_ON_DISCONNECT`
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
 struct GlobalEventHandler {}
 impl openaction::GlobalEventHandler for GlobalEventHandler {
     async fn plugin_ready(
@@ -28,6 +59,19 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     ) -> EventHandlerResult {
         let tracker = TRACKER.lock().await.clone();
 
+        CONNECTED.store(true, Ordering::SeqCst);
+
+        // Reconnected after OpenDeck went away, the devices just need to be registered again
+        // and redraw their progress bars. That goes through the outbound manager and the
+        // device tasks, so it's done outside of this event handler.
+        if TOKENS.read().await.contains_key("_watcher_task") {
+            log::info!("Reconnected to OpenDeck");
+            tracker.spawn(reregister_devices());
+            tracker.spawn(notify_reconnected());
+
+            return Ok(());
+        }
+
         let token = CancellationToken::new();
         tracker.spawn(watcher_task(token.clone()));
 
@@ -36,6 +80,36 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
             .await
             .insert("_watcher_task".to_string(), token);
 
+        let token = CancellationToken::new();
+        tracker.spawn(reconcile_task(token.clone()));
+
+        TOKENS
+            .write()
+            .await
+            .insert("_reconcile_task".to_string(), token);
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let token = CancellationToken::new();
+            tracker.spawn(signals_task(token.clone()));
+
+            TOKENS
+                .write()
+                .await
+                .insert("_signals_task".to_string(), token);
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        if let Some(path) = &CONFIG.control_socket {
+            let token = CancellationToken::new();
+            tracker.spawn(control::control_task(path.clone(), token.clone()));
+
+            TOKENS
+                .write()
+                .await
+                .insert("_control_task".to_string(), token);
+        }
+
         log::info!("Plugin initialized");
 
         Ok(())
@@ -48,14 +122,7 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     ) -> EventHandlerResult {
         let id = event.device.clone();
 
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            handle_set_image(device, event)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
-        }
+        send_to_device(&id, DeviceMessage::SetImage(event.into())).await;
 
         Ok(())
     }
@@ -67,17 +134,11 @@ impl openaction::GlobalEventHandler for GlobalEventHandler {
     ) -> EventHandlerResult {
         log::debug!("Asked to set brightness: {:#?}", event);
 
-        let id = event.device.clone();
-
-        if let Some(device) = DEVICES.read().await.get(&event.device) {
-            device
-                .set_brightness(event.brightness)
-                .await
-                .map_err(async |err| handle_error(&id, err).await)
-                .ok();
-        } else {
-            log::error!("Received event for unknown device: {}", event.device);
-        }
+        send_to_device(
+            &event.device,
+            DeviceMessage::SetBrightness(clamp_brightness(event.brightness)),
+        )
+        .await;
 
         Ok(())
     }
@@ -94,41 +155,120 @@ async fn shutdown() {
     }
 }
 
+/// Runs the connection to OpenDeck, returning once the plugin should shut down.
+///
+/// Unless `AKP05_ON_DISCONNECT` is `exit`, a dropped connection is applied to the devices
+/// and retried every `RECONNECT_DELAY`, so the plugin survives OpenDeck restarting.
 async fn connect() {
-    if let Err(error) = init_plugin(GlobalEventHandler {}, ActionEventHandler {}).await {
-        log::error!("Failed to initialize plugin: {}", error);
-        exit(1);
+    loop {
+        let result = init_plugin(GlobalEventHandler {}, ActionEventHandler {}).await;
+
+        if CONFIG.on_disconnect == DisconnectAction::Exit {
+            if let Err(error) = result {
+                log::error!("Failed to initialize plugin: {}", error);
+                exit(1);
+            }
+
+            return;
+        }
+
+        match result {
+            Ok(()) => log::warn!("Connection to OpenDeck closed"),
+            Err(error) => log::warn!("Connection to OpenDeck failed: {}", error),
+        }
+
+        if CONNECTED.swap(false, Ordering::SeqCst) {
+            notify_connection_lost().await;
+        }
+
+        log::info!("Reconnecting to OpenDeck in {:?}", RECONNECT_DELAY);
+        sleep(RECONNECT_DELAY).await;
     }
 }
 
+/// Resolves on SIGTERM. If the handler can't be set up, e.g. on a locked-down system, the
+/// plugin keeps running and only shuts down when OpenDeck goes away.
 #[cfg(any(target_os = "linux", target_os = "macos"))]
-async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
-    let mut sig = signal(SignalKind::terminate())?;
+async fn sigterm() {
+    let mut sig = match signal(SignalKind::terminate()) {
+        Ok(sig) => sig,
+        Err(err) => {
+            log::warn!(
+                "Failed to set up SIGTERM handler, continuing without it: {}",
+                err
+            );
+            std::future::pending::<()>().await;
+            return;
+        }
+    };
 
     sig.recv().await;
+}
 
-    Ok(())
+/// Handles SIGHUP to re-register all devices with OpenDeck, SIGUSR1 to log their state and
+/// SIGUSR2 to show a test pattern on them
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn signals_task(token: CancellationToken) {
+    let (mut hangup, mut user1, mut user2) = match (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) {
+        (Ok(hangup), Ok(user1), Ok(user2)) => (hangup, user1, user2),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => {
+            log::warn!("Failed to set up signal handlers: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        tokio::select! {
+            Some(()) = hangup.recv() => {
+                log::info!("Received SIGHUP, re-registering devices");
+                reregister_devices().await;
+            }
+            Some(()) = user1.recv() => {
+                log::info!("Received SIGUSR1, logging device states");
+                log_device_states().await;
+            }
+            Some(()) = user2.recv() => {
+                log::info!("Received SIGUSR2, showing test patterns");
+                show_test_patterns().await;
+            }
+            _ = token.cancelled() => break,
+        }
+    }
 }
 
 #[cfg(target_os = "windows")]
-async fn sigterm() -> Result<(), Box<dyn std::error::Error>> {
+async fn sigterm() {
     // Future that would never resolve, so select only acts on OpenDeck connection loss
     // TODO: Proper windows termination handling
     std::future::pending::<()>().await;
-
-    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let log_level = config::log_level();
+
     simplelog::TermLogger::init(
-        simplelog::LevelFilter::Debug,
+        log_level.clone().unwrap_or(config::DEFAULT_LOG_LEVEL),
         simplelog::Config::default(),
         simplelog::TerminalMode::Stdout,
         simplelog::ColorChoice::Never,
     )
     .unwrap();
 
+    if let Err(raw) = log_level {
+        log::warn!(
+            "Invalid value {:?} for AKP05_LOG_LEVEL, using default {:?}",
+            raw,
+            config::DEFAULT_LOG_LEVEL
+        );
+    }
+
+    version::log_build_info();
+
     tokio::select! {
         _ = connect() => {},
         _ = sigterm() => {},
@@ -143,7 +283,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("Waiting for tasks to finish");
 
     tracker.close();
-    tracker.wait().await;
+
+    if timeout(SHUTDOWN_TIMEOUT, tracker.wait()).await.is_err() {
+        // Device tasks release their id when done, so whatever is left is stuck
+        let pending: Vec<String> = TOKENS
+            .read()
+            .await
+            .keys()
+            .filter(|id| !id.starts_with('_'))
+            .cloned()
+            .collect();
+
+        log::warn!(
+            "Tasks did not finish in time, exiting anyway. Pending devices: {:?}",
+            pending
+        );
+
+        return Ok(());
+    }
 
     log::info!("Tasks are finished, exiting now");
 
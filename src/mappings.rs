@@ -1,8 +1,13 @@
+use std::time::Duration;
+
 use mirajazz::{
-    device::DeviceQuery,
+    device::{Device, DeviceQuery},
+    error::MirajazzError,
     types::{HidDeviceInfo, ImageFormat, ImageMirroring, ImageMode, ImageRotation},
 };
 
+use crate::config::{CONFIG, Mirroring, Orientation};
+
 // Must be unique between all the plugins, 2 characters long and match `DeviceNamespace` field in `manifest.json`
 pub const DEVICE_NAMESPACE: &str = "n4";
 
@@ -26,12 +31,162 @@ pub const ROW_COUNT: usize = 2;
 pub const COL_COUNT: usize = 5;
 pub const KEY_COUNT: usize = 15; // Hardware uses indices 0-14 (4 touch buttons + 10 regular buttons)
 pub const ENCODER_COUNT: usize = 4;
+pub const TOUCH_ZONE_COUNT: usize = 4; // Wide buttons at hardware indices 0-3, one per encoder
+
+/// Maps an OpenDeck grid position to the hardware button index.
+///
+/// Hardware rows are reversed from the OpenDeck layout:
+/// OpenDeck layout:      Hardware layout:
+/// [0] [1] [2] [3] [4]   [10] [11] [12] [13] [14]  <- Top row
+/// [5] [6] [7] [8] [9]   [5]  [6]  [7]  [8]  [9]   <- Bottom row
+///
+/// Positions outside of the grid are passed through unchanged.
+pub const fn hardware_button_index(position: u8) -> u8 {
+    match position {
+        0..=4 => position + 10, // Top row: OpenDeck 0-4 → Hardware 10-14
+        5..=9 => position,      // Bottom row: OpenDeck 5-9 → Hardware 5-9
+        _ => position,          // Invalid, pass through
+    }
+}
+
+// The remap is easy to break and only observable on real hardware, so pin it at compile time
+const _: () = {
+    assert!(hardware_button_index(0) == 10);
+    assert!(hardware_button_index(4) == 14);
+    assert!(hardware_button_index(5) == 5);
+    assert!(hardware_button_index(9) == 9);
+    assert!(hardware_button_index(10) == 10);
+    assert!(hardware_button_index(255) == 255);
+};
+
+/// Maps an OpenDeck grid position to where it is on an upside-down panel of the kind, and back.
+///
+/// Applied on top of `hardware_button_index` for rendering and on the reported position for
+/// input, so the remap always matches the image rotation. Positions outside of the grid are
+/// passed through unchanged.
+pub fn oriented_position(kind: &Kind, position: u8) -> u8 {
+    let last = (kind.rows() * kind.cols() - 1) as u8;
+
+    match CONFIG.orientation {
+        Orientation::UpsideDown if position <= last => last - position,
+        _ => position,
+    }
+}
+
+/// Maps an encoder index to where it is on an upside-down panel of the kind, and back.
+///
+/// Touch zones sit above their encoders, so this also applies to touch zone indices.
+pub fn oriented_encoder(kind: &Kind, encoder: usize) -> usize {
+    let count = kind.encoder_count();
+
+    match CONFIG.orientation {
+        Orientation::UpsideDown if encoder < count => count - 1 - encoder,
+        _ => encoder,
+    }
+}
+
+/// Maps an encoder index to the touch zone showing its image, with `AKP05_TOUCH_ZONES` applied
+/// on top of the orientation. Encoders the kind doesn't have have no touch zone.
+pub fn touch_zone(kind: &Kind, encoder: usize) -> Option<usize> {
+    let count = kind.encoder_count();
+
+    if encoder >= count {
+        return None;
+    }
+
+    let zone = CONFIG
+        .touch_zones
+        .get(encoder)
+        .map_or(encoder, |zone| *zone as usize);
+
+    (zone < count).then(|| oriented_encoder(kind, zone))
+}
+
+/// Maps a touch zone index to the encoder whose image it shows, the reverse of `touch_zone`
+pub fn zone_encoder(kind: &Kind, zone: usize) -> Option<usize> {
+    let count = kind.encoder_count();
+
+    if zone >= count {
+        return None;
+    }
+
+    let zone = oriented_encoder(kind, zone);
+    let encoder = CONFIG
+        .touch_zones
+        .iter()
+        .position(|other| *other as usize == zone)
+        .unwrap_or(zone);
+
+    (encoder < count).then_some(encoder)
+}
+
+/// Converts the mirroring from `AKP05_IMAGE_MIRRORS` to the one of the device library
+pub fn image_mirroring(mirroring: Mirroring) -> ImageMirroring {
+    match mirroring {
+        Mirroring::None => ImageMirroring::None,
+        Mirroring::Horizontal => ImageMirroring::X,
+        Mirroring::Vertical => ImageMirroring::Y,
+        Mirroring::Both => ImageMirroring::Both,
+    }
+}
+
+/// Adds the mounting rotation to the rotation a kind needs when sitting upright
+fn oriented_rotation(rotation: ImageRotation) -> ImageRotation {
+    match CONFIG.orientation {
+        Orientation::Normal => rotation,
+        Orientation::UpsideDown => match rotation {
+            ImageRotation::Rot0 => ImageRotation::Rot180,
+            ImageRotation::Rot90 => ImageRotation::Rot270,
+            ImageRotation::Rot180 => ImageRotation::Rot0,
+            ImageRotation::Rot270 => ImageRotation::Rot90,
+        },
+    }
+}
 
 // OpenDeck device type: 7 = StreamDeckPlus (with encoders and touch zones)
 // This enables automatic encoder function rendering on the 4 wide touch zone buttons
 pub const DEVICE_TYPE: u8 = 7;
 
-#[derive(Debug, Clone)]
+// The layout constants depend on each other, so a change to one of them that isn't reflected
+// in the others fails the build instead of producing a subtly wrong registration
+const _: () = {
+    assert!(
+        ENCODER_COUNT == TOUCH_ZONE_COUNT,
+        "every encoder needs a touch zone"
+    );
+    assert!(
+        DEVICE_TYPE != 7 || ENCODER_COUNT > 0,
+        "StreamDeckPlus type needs encoders"
+    );
+    assert!(ROW_COUNT * COL_COUNT <= u8::MAX as usize);
+    assert!(ENCODER_COUNT <= u8::MAX as usize);
+
+    // Every grid position maps to its own hardware index, past the touch zones
+    let mut position = 0;
+    while position < ROW_COUNT * COL_COUNT {
+        let index = hardware_button_index(position as u8) as usize;
+        assert!(index >= TOUCH_ZONE_COUNT && index < KEY_COUNT);
+
+        let mut other = 0;
+        while other < position {
+            assert!(hardware_button_index(other as u8) as usize != index);
+            other += 1;
+        }
+
+        position += 1;
+    }
+};
+
+/// How a kind's touch zone screens are driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TouchZoneMode {
+    /// Discrete wide buttons at hardware indices 0-3, written like any other button
+    Buttons,
+    /// One programmable strip, each touch zone written with `write_lcd` at its own offset
+    Strip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Kind {
     Akp05,
     N4,
@@ -46,16 +201,172 @@ pub const N4_PID: u16 = 0x1007;
 pub const AJAZZ_VID: u16 = 0x0300;
 pub const AKP05_PID: u16 = 0x3004;
 
-// Usage page and usage id need verification with actual hardware testing
-// TODO: Verify usage page (65440) and usage id (1) are correct for N4 and AKP05
-pub const AKP05_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, AJAZZ_VID, AKP05_PID);
-pub const N4_QUERY: DeviceQuery = DeviceQuery::new(65440, 1, MIRABOX_VID, N4_PID);
+pub const KINDS: [Kind; 2] = [Kind::Akp05, Kind::N4];
 
-pub const QUERIES: [DeviceQuery; 2] = [AKP05_QUERY, N4_QUERY];
+/// Queries for the kinds that are looked for, see `Kind::is_enabled`
+pub fn queries() -> Vec<DeviceQuery> {
+    KINDS
+        .iter()
+        .filter(|kind| kind.is_enabled())
+        .map(Kind::query)
+        .collect()
+}
+
+// Devices are picked up and tracked by this table alone, and the placeholder AKP05 ids make it
+// easy to match the wrong device (or none), so pin which pairs are accepted at compile time
+const _: () = {
+    assert!(matches!(
+        Kind::from_vid_pid(MIRABOX_VID, N4_PID),
+        Some(Kind::N4)
+    ));
+    assert!(matches!(
+        Kind::from_vid_pid(AJAZZ_VID, AKP05_PID),
+        Some(Kind::Akp05)
+    ));
+
+    // A known vendor or product id alone isn't enough
+    assert!(Kind::from_vid_pid(MIRABOX_VID, AKP05_PID).is_none());
+    assert!(Kind::from_vid_pid(AJAZZ_VID, N4_PID).is_none());
+    assert!(Kind::from_vid_pid(MIRABOX_VID, N4_PID + 1).is_none());
+    assert!(Kind::from_vid_pid(0, 0).is_none());
+    assert!(Kind::from_vid_pid(u16::MAX, u16::MAX).is_none());
+
+    // Every kind's ids lead back to it, so whatever the queries find is accepted
+    let mut i = 0;
+    while i < KINDS.len() {
+        let (vid, pid) = KINDS[i].vid_pid();
+
+        assert!(matches!(
+            (Kind::from_vid_pid(vid, pid), &KINDS[i]),
+            (Some(Kind::Akp05), Kind::Akp05) | (Some(Kind::N4), Kind::N4)
+        ));
+
+        i += 1;
+    }
+};
+
+// The layout constants above are the most any kind has, the per-encoder state is sized by them
+const _: () = {
+    let mut i = 0;
+    while i < KINDS.len() {
+        let kind = &KINDS[i];
+
+        assert!(kind.rows() * kind.cols() <= ROW_COUNT * COL_COUNT);
+        assert!(
+            kind.encoder_count() <= ENCODER_COUNT,
+            "per-encoder state is sized by ENCODER_COUNT"
+        );
+
+        // Input reports are sized by the kind, so every button of its grid has to fit in them
+        let mut position = 0;
+        while position < kind.rows() * kind.cols() {
+            assert!((hardware_button_index(position as u8) as usize) < kind.key_count());
+            position += 1;
+        }
+
+        // Skipping an index that is in use would leave a button uncleared
+        let unused = kind.unused_indices();
+        let mut j = 0;
+        while j < unused.len() {
+            assert!((unused[j] as usize) >= TOUCH_ZONE_COUNT);
+            assert!((unused[j] as usize) < KEY_COUNT);
+
+            let mut position = 0;
+            while position < ROW_COUNT * COL_COUNT {
+                assert!(hardware_button_index(position as u8) != unused[j]);
+                position += 1;
+            }
+
+            j += 1;
+        }
+
+        i += 1;
+    }
+};
 
 impl Kind {
+    /// USB vendor and product id of the kind
+    pub const fn vid_pid(&self) -> (u16, u16) {
+        match self {
+            Self::Akp05 => (AJAZZ_VID, AKP05_PID),
+            Self::N4 => (MIRABOX_VID, N4_PID),
+        }
+    }
+
+    /// Rows of the button grid registered with OpenDeck
+    pub const fn rows(&self) -> usize {
+        match self {
+            Self::Akp05 => ROW_COUNT, // TODO: Verify with actual AKP05 hardware
+            Self::N4 => ROW_COUNT,
+        }
+    }
+
+    /// Columns of the button grid registered with OpenDeck
+    pub const fn cols(&self) -> usize {
+        match self {
+            Self::Akp05 => COL_COUNT, // TODO: Verify with actual AKP05 hardware
+            Self::N4 => COL_COUNT,
+        }
+    }
+
+    /// Number of hardware button indices, including the touch zone buttons
+    pub const fn key_count(&self) -> usize {
+        match self {
+            Self::Akp05 => KEY_COUNT, // TODO: Verify with actual AKP05 hardware
+            Self::N4 => KEY_COUNT,
+        }
+    }
+
+    /// Hardware indices below `key_count` that have no button behind them
+    pub const fn unused_indices(&self) -> &'static [u8] {
+        match self {
+            Self::Akp05 => &[4], // TODO: Verify with actual AKP05 hardware
+            Self::N4 => &[4],
+        }
+    }
+
+    /// Number of encoders, each with a touch zone above it
+    pub const fn encoder_count(&self) -> usize {
+        match self {
+            Self::Akp05 => ENCODER_COUNT, // TODO: Verify with actual AKP05 hardware
+            Self::N4 => ENCODER_COUNT,
+        }
+    }
+
+    /// HID usage page and usage id of the interface the device is driven through.
+    ///
+    /// If these are wrong, the device is never found, see `diagnose_missing_devices`.
+    pub const fn usage(&self) -> (u16, u16) {
+        match self {
+            Self::Akp05 => (65440, 1), // TODO: Verify with actual AKP05 hardware
+            Self::N4 => (65440, 1),    // TODO: Verify with N4 hardware testing
+        }
+    }
+
+    /// Whether the ids of the kind are confirmed with actual hardware
+    pub const fn is_verified(&self) -> bool {
+        match self {
+            Self::Akp05 => false, // TODO: Flip once the AKP05 ids are known
+            Self::N4 => true,
+        }
+    }
+
+    /// Whether devices of the kind are looked for. Unverified ids could match an unrelated
+    /// device, so those kinds need `AKP05_UNVERIFIED_KINDS`
+    pub fn is_enabled(&self) -> bool {
+        self.is_verified() || CONFIG.unverified_kinds
+    }
+
+    /// Query matching the HID interface of the kind
+    pub const fn query(&self) -> DeviceQuery {
+        let (vid, pid) = self.vid_pid();
+        let (usage_page, usage_id) = self.usage();
+
+        DeviceQuery::new(usage_page, usage_id, vid, pid)
+    }
+
     /// Matches devices VID+PID pairs to correct kinds
-    pub fn from_vid_pid(vid: u16, pid: u16) -> Option<Self> {
+    pub const fn from_vid_pid(vid: u16, pid: u16) -> Option<Self> {
         match vid {
             AJAZZ_VID => match pid {
                 AKP05_PID => Some(Kind::Akp05),
@@ -89,13 +400,49 @@ impl Kind {
         }
     }
 
+    /// Device-specific handshake needed before the device accepts images, run right after
+    /// connecting. Brightness and clearing the buttons are done for all kinds afterwards.
+    pub async fn init_sequence(&self, _device: &Device) -> Result<(), MirajazzError> {
+        match self {
+            Self::Akp05 => Ok(()), // TODO: Verify whether AKP05 needs a wake command
+            Self::N4 => Ok(()),
+        }
+    }
+
+    /// How the touch zones above the encoders take images.
+    ///
+    /// Current N4 firmware accepts `write_lcd` but ignores it, so both kinds use buttons.
+    pub fn touch_zone_mode(&self) -> TouchZoneMode {
+        match self {
+            Self::Akp05 => TouchZoneMode::Buttons, // TODO: Check if AKP05 firmware has a strip
+            Self::N4 => TouchZoneMode::Buttons,
+        }
+    }
+
+    /// How long the panel needs after initialization before it reliably takes images
+    pub fn settle_delay(&self) -> Duration {
+        match self {
+            Self::Akp05 => Duration::from_millis(100), // TODO: Verify with AKP05 hardware
+            Self::N4 => Duration::from_millis(100),
+        }
+    }
+
     /// Image format for regular LCD buttons (2x5 grid, positions 0-9)
     pub fn image_format(&self) -> ImageFormat {
         ImageFormat {
             mode: ImageMode::JPEG,
             size: (112, 112),
-            rotation: ImageRotation::Rot180,
-            mirror: ImageMirroring::None,
+            rotation: oriented_rotation(ImageRotation::Rot180),
+            mirror: self.mirror(),
+        }
+    }
+
+    /// Mirroring the panels of the kind need on top of their rotation, unless overridden with
+    /// `AKP05_IMAGE_MIRRORS`
+    pub const fn mirror(&self) -> ImageMirroring {
+        match self {
+            Self::Akp05 => ImageMirroring::None, // TODO: Verify with actual AKP05 hardware
+            Self::N4 => ImageMirroring::None,
         }
     }
 
@@ -106,10 +453,34 @@ impl Kind {
         ImageFormat {
             mode: ImageMode::JPEG,
             size: (184, 120),
-            rotation: ImageRotation::Rot180,
-            mirror: ImageMirroring::None,
+            rotation: oriented_rotation(ImageRotation::Rot180),
+            mirror: self.mirror(),
         }
     }
+
+    /// Formats tried in order for regular buttons, for panel revisions rejecting the first one
+    pub fn image_formats(&self) -> Vec<ImageFormat> {
+        fallback_formats(self.image_format())
+    }
+
+    /// Formats tried in order for touch zone buttons, like `image_formats`
+    pub fn image_formats_touchzone(&self) -> Vec<ImageFormat> {
+        fallback_formats(self.image_format_touchzone())
+    }
+}
+
+/// The primary format, followed by BMP if enabled with `AKP05_BMP_FALLBACK`
+fn fallback_formats(primary: ImageFormat) -> Vec<ImageFormat> {
+    let mut formats = vec![primary];
+
+    if CONFIG.bmp_fallback {
+        formats.push(ImageFormat {
+            mode: ImageMode::BMP,
+            ..primary
+        });
+    }
+
+    formats
 }
 
 #[derive(Debug, Clone)]
@@ -118,3 +489,33 @@ pub struct CandidateDevice {
     pub dev: HidDeviceInfo,
     pub kind: Kind,
 }
+
+impl CandidateDevice {
+    /// Checks if a user-provided selector refers to this device, by id, serial or HID device id
+    pub fn matches(&self, selector: &str) -> bool {
+        self.id == selector
+            || self.dev.serial_number.as_deref() == Some(selector)
+            || format!("{:?}", self.dev.id) == selector
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoders_past_the_kind_have_no_touch_zone() {
+        for kind in KINDS {
+            let count = kind.encoder_count();
+
+            for encoder in 0..count {
+                assert_eq!(touch_zone(&kind, encoder), Some(encoder));
+                assert_eq!(zone_encoder(&kind, encoder), Some(encoder));
+            }
+
+            assert_eq!(touch_zone(&kind, count), None);
+            assert_eq!(zone_encoder(&kind, count), None);
+            assert_eq!(touch_zone(&kind, u8::MAX as usize), None);
+        }
+    }
+}
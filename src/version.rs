@@ -0,0 +1,71 @@
+#[cfg(not(target_os = "windows"))]
+use serde_json::{Value, json};
+
+use crate::{config::CONFIG, mappings::KINDS};
+
+/// Crate version from `Cargo.toml`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short hash of the commit the plugin was built from, set by the build script
+pub const GIT_HASH: &str = env!("AKP05_GIT_HASH");
+
+/// Logs the build and the device ids compiled into it, so bug reports tell which ones a user
+/// runs while many of them are still unverified
+pub fn log_build_info() {
+    log::info!("opendeck-akp05 {} ({})", VERSION, GIT_HASH);
+
+    for kind in KINDS {
+        let (vid, pid) = kind.vid_pid();
+        let (usage_page, usage_id) = kind.usage();
+
+        log::info!(
+            "Supports {}: VID {:04X}, PID {:04X}, usage page {:04X}, usage {:04X}",
+            kind.human_name(),
+            vid,
+            pid,
+            usage_page,
+            usage_id
+        );
+
+        if kind.is_verified() {
+            continue;
+        }
+
+        if CONFIG.unverified_kinds {
+            log::warn!(
+                "{} support is EXPERIMENTAL: its ids are unverified placeholders that may match \
+                 an unrelated device",
+                kind.human_name()
+            );
+        } else {
+            log::info!(
+                "Not looking for {} as its ids are unverified, AKP05_UNVERIFIED_KINDS enables it",
+                kind.human_name()
+            );
+        }
+    }
+}
+
+/// The same as `log_build_info`, for the control socket
+#[cfg(not(target_os = "windows"))]
+pub fn build_info() -> Value {
+    let kinds: Vec<Value> = KINDS
+        .iter()
+        .map(|kind| {
+            let (vid, pid) = kind.vid_pid();
+            let (usage_page, usage_id) = kind.usage();
+
+            json!({
+                "kind": kind.human_name(),
+                "vid": vid,
+                "pid": pid,
+                "usage_page": usage_page,
+                "usage_id": usage_id,
+                "verified": kind.is_verified(),
+                "enabled": kind.is_enabled(),
+            })
+        })
+        .collect();
+
+    json!({ "version": VERSION, "git_hash": GIT_HASH, "kinds": kinds })
+}
@@ -1,16 +1,23 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
 use futures_lite::StreamExt;
 use mirajazz::{
-    device::{DeviceWatcher, list_devices},
+    device::{DeviceQuery, DeviceWatcher, list_devices},
     error::MirajazzError,
     types::{DeviceLifecycleEvent, HidDeviceInfo},
 };
-use openaction::OUTBOUND_EVENT_MANAGER;
-use tokio_util::sync::CancellationToken;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use crate::{
-    DEVICES, TOKENS, TRACKER,
-    device::device_task,
-    mappings::{CandidateDevice, DEVICE_NAMESPACE, Kind, QUERIES},
+    TOKENS, TRACKER,
+    config::CONFIG,
+    device::{cancel_device, deregister_device, device_task, release_device},
+    mappings::{CandidateDevice, DEVICE_NAMESPACE, KINDS, Kind, queries},
 };
 
 fn sanitize_identifier(raw: &str, max_len: usize) -> Option<String> {
@@ -32,79 +39,384 @@ fn normalised_serial(serial: Option<&String>) -> Option<String> {
         .and_then(|s| sanitize_identifier(s, 32))
 }
 
-fn fallback_serial(dev: &HidDeviceInfo, kind: &Kind) -> String {
-    let mut suffix = format!("{:04X}{:04X}", dev.vendor_id, dev.product_id);
+/// What a device is told apart by, taken from its HID info
+struct DeviceIdentity<'a> {
+    vendor_id: u16,
+    product_id: u16,
+    serial: Option<&'a String>,
+    /// Platform specific HID device id, as shown in the logs
+    hid_id: String,
+}
+
+impl<'a> DeviceIdentity<'a> {
+    fn of(dev: &'a HidDeviceInfo) -> Self {
+        Self {
+            vendor_id: dev.vendor_id,
+            product_id: dev.product_id,
+            serial: dev.serial_number.as_ref(),
+            hid_id: format!("{:?}", dev.id),
+        }
+    }
+}
+
+fn fallback_serial(identity: &DeviceIdentity, kind: &Kind) -> String {
+    let mut suffix = format!("{:04X}{:04X}", identity.vendor_id, identity.product_id);
 
     if let Some(kind_tag) = sanitize_identifier(&format!("{:?}", kind), 8) {
         suffix.push_str(&kind_tag);
     }
 
-    if let Some(id_fragment) = sanitize_identifier(&format!("{:?}", dev.id), 16) {
+    if let Some(id_fragment) = sanitize_identifier(&identity.hid_id, 16) {
         suffix.push_str(&id_fragment);
     }
 
     suffix
 }
 
-fn device_id_for(dev: &HidDeviceInfo, kind: &Kind) -> String {
-    let suffix =
-        normalised_serial(dev.serial_number.as_ref()).unwrap_or_else(|| fallback_serial(dev, kind));
+/// Looks up an id pinned with `AKP05_DEVICE_IDS`, by VID:PID or HID device id
+fn pinned_id(identity: &DeviceIdentity) -> Option<String> {
+    let vid_pid = format!("{:04X}:{:04X}", identity.vendor_id, identity.product_id);
+
+    CONFIG
+        .device_ids
+        .iter()
+        .find(|(selector, _)| {
+            selector.eq_ignore_ascii_case(&vid_pid) || **selector == identity.hid_id
+        })
+        .and_then(|(_, id)| sanitize_identifier(id, 32))
+}
+
+fn device_id_for(identity: &DeviceIdentity, kind: &Kind) -> String {
+    // A pinned id survives firmware updates that change the serial
+    let suffix = pinned_id(identity)
+        .or_else(|| normalised_serial(identity.serial))
+        .unwrap_or_else(|| fallback_serial(identity, kind));
 
     format!("{}-{}", DEVICE_NAMESPACE, suffix)
 }
 
+/// Resolves the kind and id of a device, if it's of a kind that is looked for
+fn kind_and_id(identity: &DeviceIdentity) -> Option<(Kind, String)> {
+    let kind =
+        Kind::from_vid_pid(identity.vendor_id, identity.product_id).filter(Kind::is_enabled)?;
+    let id = device_id_for(identity, &kind);
+
+    Some((kind, id))
+}
+
 fn device_info_to_candidate(dev: HidDeviceInfo) -> Option<CandidateDevice> {
-    let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
-    let id = device_id_for(&dev, &kind);
+    let (kind, id) = kind_and_id(&DeviceIdentity::of(&dev))?;
 
     Some(CandidateDevice { id, dev, kind })
 }
 
+/// Checks the candidate against `AKP05_ONLY_DEVICE`, if set
+fn is_selected(candidate: &CandidateDevice) -> bool {
+    CONFIG
+        .only_device
+        .as_deref()
+        .is_none_or(|selected| candidate.matches(selected))
+}
+
 fn device_info_to_id(dev: &HidDeviceInfo) -> Option<String> {
     let kind = Kind::from_vid_pid(dev.vendor_id, dev.product_id)?;
-    Some(device_id_for(dev, &kind))
+    Some(device_id_for(&DeviceIdentity::of(dev), &kind))
 }
 
 /// Returns devices that matches known pid/vid pairs
 async fn get_candidates() -> Result<Vec<CandidateDevice>, MirajazzError> {
     log::info!("Looking for candidate devices");
 
-    let mut candidates: Vec<CandidateDevice> = Vec::new();
+    let devices = list_devices(&queries()).await?;
 
-    for dev in list_devices(&QUERIES).await? {
-        if let Some(candidate) = device_info_to_candidate(dev.clone()) {
-            candidates.push(candidate);
-        } else {
+    if devices.is_empty() {
+        diagnose_missing_devices().await;
+    }
+
+    let candidates = devices
+        .into_iter()
+        .filter_map(device_info_to_candidate)
+        .filter(|candidate| {
+            let selected = is_selected(candidate);
+
+            if !selected {
+                log::info!("Ignoring device {} as it's not selected", candidate.id);
+            }
+
+            selected
+        });
+
+    Ok(dedup_by_id(candidates, |candidate| candidate.id.as_str()))
+}
+
+/// Keeps the first of the entries sharing an id.
+///
+/// Devices with several HID interfaces can be listed once for each of them.
+fn dedup_by_id<T: Debug>(items: impl IntoIterator<Item = T>, id: impl Fn(&T) -> &str) -> Vec<T> {
+    let mut unique: Vec<T> = Vec::new();
+
+    for item in items {
+        if unique.iter().any(|other| id(other) == id(&item)) {
+            log::debug!(
+                "Skipping duplicate entry for device {}: {:?}",
+                id(&item),
+                item
+            );
             continue;
         }
+
+        unique.push(item);
+    }
+
+    unique
+}
+
+/// Whether `diagnose_missing_devices` already ran, once is enough to tell what is wrong
+static DIAGNOSED: AtomicBool = AtomicBool::new(false);
+
+/// Logs HID interfaces of supported devices that only differ in usage page or usage id.
+///
+/// A wrong usage page or id in `Kind::usage` makes a device silently never show up. The
+/// device library only lists devices matching a query, so each kind is probed with the usage
+/// pages and ids declared for the other kinds, and the ones found tell what the kind should use.
+/// Scans without devices are routine while none is plugged in, so this only runs for the first.
+async fn diagnose_missing_devices() {
+    if DIAGNOSED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let pages: BTreeSet<u16> = KINDS.iter().map(|kind| kind.usage().0).collect();
+    let ids: BTreeSet<u16> = KINDS.iter().map(|kind| kind.usage().1).collect();
+    let mut probes = Vec::new();
+
+    for kind in KINDS.iter().filter(|kind| kind.is_enabled()) {
+        let (vid, pid) = kind.vid_pid();
+
+        for usage_page in &pages {
+            for usage_id in &ids {
+                if (*usage_page, *usage_id) != kind.usage() {
+                    probes.push(DeviceQuery::new(*usage_page, *usage_id, vid, pid));
+                }
+            }
+        }
+    }
+
+    if probes.is_empty() {
+        log::debug!("All kinds use the same usage page and id, nothing else to probe");
+        return;
+    }
+
+    let devices = match list_devices(&probes).await {
+        Ok(devices) => devices,
+        Err(err) => {
+            log::debug!("Could not probe for other HID interfaces: {}", err);
+            return;
+        }
+    };
+
+    if devices.is_empty() {
+        log::info!("No supported devices found on the usage pages of other kinds either");
+    }
+
+    for dev in devices {
+        let Some(kind) = Kind::from_vid_pid(dev.vendor_id, dev.product_id) else {
+            continue;
+        };
+
+        log::warn!(
+            "Found {} on a usage page or usage id other than the expected {:?}, \
+             it may need to be updated: {:?}",
+            kind.human_name(),
+            kind.usage(),
+            dev
+        );
+    }
+}
+
+/// Reserves the candidate id and spawns its device task.
+async fn spawn_device_task(tracker: &TaskTracker, candidate: CandidateDevice) {
+    let Some(token) = reserve_device(&candidate.id).await else {
+        log::debug!("Not spawning a task for {:?}", candidate.dev);
+        return;
+    };
+
+    tracker.spawn(supervise_device_task(candidate, token));
+}
+
+/// Inserts a fresh token for the id, unless it's already taken or the device limit is reached.
+///
+/// The token is inserted before the task starts, so a second device (or a duplicate
+/// lifecycle event) resolving to the same id is rejected while the first one is still
+/// initializing, instead of overwriting its token and leaving it impossible to cancel.
+pub async fn reserve_device(id: &str) -> Option<CancellationToken> {
+    let mut tokens = TOKENS.write().await;
+
+    match tokens.get(id) {
+        // Expected for every connected device when the watcher scans again after a restart
+        Some(token) if !token.is_cancelled() => {
+            log::debug!("Device {} is already managed, ignoring it", id);
+            return None;
+        }
+        Some(_) => {
+            log::warn!("Device {} is still shutting down, ignoring it", id);
+            return None;
+        }
+        None => {}
+    }
+
+    // Tokens of the plugin's own tasks are prefixed with `_`
+    let devices = tokens.keys().filter(|id| !id.starts_with('_')).count();
+
+    if devices >= CONFIG.max_devices {
+        log::warn!(
+            "Already managing {} devices, the maximum, ignoring {}",
+            devices,
+            id
+        );
+        return None;
+    }
+
+    let token = CancellationToken::new();
+    tokens.insert(id.to_string(), token.clone());
+
+    Some(token)
+}
+
+/// Runs the task of a device, starting it over with a backoff if it panics.
+///
+/// A panic would otherwise leave the id reserved and the device silently dead until replugged.
+async fn supervise_device_task(candidate: CandidateDevice, token: CancellationToken) {
+    let mut delay = RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+
+        // Spawned on its own so a panic ends up in the join result instead of unwinding here
+        let result = tokio::spawn(device_task(candidate.clone(), token.clone())).await;
+
+        let err = match result {
+            Ok(()) => return,
+            Err(err) if err.is_panic() => err,
+            Err(err) => {
+                log::error!("Device task for {} failed: {}", candidate.id, err);
+                break;
+            }
+        };
+
+        log::error!("Device task for {} panicked: {}", candidate.id, err);
+
+        if !CONFIG.restart_panicked || token.is_cancelled() {
+            break;
+        }
+
+        if started.elapsed() > RESTART_DELAY_MAX {
+            delay = RESTART_DELAY;
+        }
+
+        log::warn!("Restarting device task for {} in {:?}", candidate.id, delay);
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = token.cancelled() => break,
+        }
+
+        delay = (delay * 2).min(RESTART_DELAY_MAX);
     }
 
-    Ok(candidates)
+    // An unplugged device was already deregistered by the watcher
+    let unplugged = token.is_cancelled();
+
+    // The task never got to clean up after itself
+    release_device(&candidate.id, &token).await;
+
+    if !unplugged {
+        deregister_device(&candidate.id).await;
+    }
 }
 
+/// Delay before the first watcher or device task restart, doubled after each consecutive failure
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const RESTART_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Supervises the device watcher, restarting it with a backoff whenever it fails.
+///
+/// Devices that are already connected keep running across restarts, since their tasks
+/// and tokens are independent from the watcher. Ones unplugged in between are let go once
+/// the watcher is back, see `forget_unplugged`.
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "watcher", skip_all))]
 pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError> {
     let tracker = TRACKER.lock().await.clone();
+    let mut delay = RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+
+        match watch(&tracker, &token).await {
+            Ok(()) if token.is_cancelled() => break,
+            Ok(()) => log::warn!("Watcher stream ended unexpectedly"),
+            Err(err) => log::error!("Watcher failed: {}", err),
+        }
+
+        // Start over with a short delay if the watcher was running fine for a while
+        if started.elapsed() > RESTART_DELAY_MAX {
+            delay = RESTART_DELAY;
+        }
 
+        log::warn!("Restarting watcher in {:?}", delay);
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = token.cancelled() => break,
+        }
+
+        delay = (delay * 2).min(RESTART_DELAY_MAX);
+    }
+
+    log::info!("Watcher is shutting down");
+
+    Ok(())
+}
+
+/// Lets go of managed devices that are missing from a scan.
+///
+/// Devices unplugged while the watcher was restarting never got a disconnect event, so
+/// their tasks would otherwise keep running and OpenDeck would keep showing them.
+async fn forget_unplugged(candidates: &[CandidateDevice]) {
+    let unplugged: Vec<String> = TOKENS
+        .read()
+        .await
+        .iter()
+        .filter(|(id, token)| !id.starts_with('_') && !token.is_cancelled())
+        .filter(|(id, _)| !candidates.iter().any(|candidate| candidate.id == **id))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    for id in unplugged {
+        log::info!("Device {} went away while the watcher was down", id);
+        cancel_device(&id, true).await;
+
+        deregister_device(&id).await;
+    }
+}
+
+/// Scans for connected devices, then handles hotplug events until cancelled or failed
+async fn watch(tracker: &TaskTracker, token: &CancellationToken) -> Result<(), MirajazzError> {
     // Scans for connected devices that (possibly) we can use
     let candidates = get_candidates().await?;
 
+    forget_unplugged(&candidates).await;
+
     log::info!("Looking for connected devices");
 
     for candidate in candidates {
         log::info!("New candidate {:#?}", candidate);
 
-        let token = CancellationToken::new();
-
-        TOKENS
-            .write()
-            .await
-            .insert(candidate.id.clone(), token.clone());
-
-        tracker.spawn(device_task(candidate, token));
+        spawn_device_task(tracker, candidate).await;
     }
 
     let mut watcher = DeviceWatcher::new();
-    let mut watcher_stream = watcher.watch(&QUERIES).await?;
+    let queries = queries();
+    let mut watcher_stream = watcher.watch(&queries).await?;
 
     log::info!("Watcher is ready");
 
@@ -120,21 +432,13 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
             match ev {
                 DeviceLifecycleEvent::Connected(info) => {
                     if let Some(candidate) = device_info_to_candidate(info) {
-                        // Don't add existing device again
-                        if DEVICES.read().await.contains_key(&candidate.id) {
+                        if !is_selected(&candidate) {
+                            log::info!("Ignoring device {} as it's not selected", candidate.id);
                             continue;
                         }
 
-                        let token = CancellationToken::new();
-
-                        TOKENS
-                            .write()
-                            .await
-                            .insert(candidate.id.clone(), token.clone());
-
                         log::debug!("Spawning task for new device: {:?}", candidate);
-                        tracker.spawn(device_task(candidate, token));
-                        log::debug!("Spawned");
+                        spawn_device_task(tracker, candidate).await;
                     }
                 }
                 DeviceLifecycleEvent::Disconnected(info) => {
@@ -147,24 +451,133 @@ pub async fn watcher_task(token: CancellationToken) -> Result<(), MirajazzError>
                         continue;
                     };
 
-                    if let Some(token) = TOKENS.write().await.remove(&id) {
-                        log::info!("Sending cancel request for {}", id);
-                        token.cancel();
-                    }
+                    log::info!("Sending cancel request for {}", id);
+                    cancel_device(&id, true).await;
 
-                    DEVICES.write().await.remove(&id);
-
-                    if let Some(outbound) = OUTBOUND_EVENT_MANAGER.lock().await.as_mut() {
-                        outbound.deregister_device(id.clone()).await.ok();
-                    }
+                    deregister_device(&id).await;
 
                     log::info!("Disconnected device {}", id);
                 }
             }
         } else {
-            log::info!("Watcher is shutting down");
-
             break Ok(());
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mappings::{MIRABOX_VID, N4_PID};
+
+    fn identity<'a>(
+        vendor_id: u16,
+        product_id: u16,
+        serial: Option<&'a String>,
+        hid_id: &str,
+    ) -> DeviceIdentity<'a> {
+        DeviceIdentity {
+            vendor_id,
+            product_id,
+            serial,
+            hid_id: hid_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn known_device_gets_kind_and_id_from_serial() {
+        let serial = " ABC-123 ".to_string();
+
+        let resolved = kind_and_id(&identity(MIRABOX_VID, N4_PID, Some(&serial), "1-2:1.0"));
+
+        assert_eq!(resolved, Some((Kind::N4, "n4-ABC123".to_string())));
+    }
+
+    #[test]
+    fn unknown_device_is_not_resolved() {
+        let serial = "ABC123".to_string();
+
+        assert_eq!(
+            kind_and_id(&identity(0x1234, 0x5678, Some(&serial), "1-2")),
+            None
+        );
+        assert_eq!(
+            kind_and_id(&identity(MIRABOX_VID, 0x5678, None, "1-2")),
+            None
+        );
+    }
+
+    #[test]
+    fn interfaces_with_the_same_serial_share_an_id() {
+        let serial = "ABC123".to_string();
+
+        let (_, first) =
+            kind_and_id(&identity(MIRABOX_VID, N4_PID, Some(&serial), "1-2:1.0")).unwrap();
+        let (_, second) =
+            kind_and_id(&identity(MIRABOX_VID, N4_PID, Some(&serial), "1-2:1.1")).unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn devices_without_serial_are_told_apart_by_hid_id() {
+        let (_, first) = kind_and_id(&identity(MIRABOX_VID, N4_PID, None, "1-2:1.0")).unwrap();
+        let (_, second) = kind_and_id(&identity(MIRABOX_VID, N4_PID, None, "1-3:1.0")).unwrap();
+
+        assert!(first.starts_with("n4-"));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn duplicate_entries_keep_the_first() {
+        let entries = [
+            ("n4-A", 1),
+            ("n4-B", 2),
+            ("n4-A", 3),
+            ("n4-B", 4),
+            ("n4-C", 5),
+        ];
+
+        let unique = dedup_by_id(entries, |(id, _)| *id);
+
+        assert_eq!(unique, [("n4-A", 1), ("n4-B", 2), ("n4-C", 5)]);
+    }
+
+    #[tokio::test]
+    async fn second_reservation_is_rejected_while_first_is_live() {
+        let id = "TESTRESERVETWICE".to_string();
+
+        let first = reserve_device(&id).await.unwrap();
+        assert!(reserve_device(&id).await.is_none());
+
+        release_device(&id, &first).await;
+        let second = reserve_device(&id).await.unwrap();
+
+        release_device(&id, &second).await;
+    }
+
+    #[tokio::test]
+    async fn stale_release_keeps_newer_owner() {
+        let id = "TESTSTALERELEASE".to_string();
+
+        // Unplugged: the old token is cancelled and the id freed right away
+        let stale = reserve_device(&id).await.unwrap();
+        cancel_device(&id, true).await;
+        assert!(stale.is_cancelled());
+
+        // Plugged back in before the old task finished
+        let newer = reserve_device(&id).await.unwrap();
+
+        // The old task shutting down must not evict the newer one
+        release_device(&id, &stale).await;
+
+        let tokens = TOKENS.read().await;
+        let owner = tokens.get(&id).unwrap();
+        assert!(!owner.is_cancelled());
+        assert!(!newer.is_cancelled());
+        drop(tokens);
+
+        release_device(&id, &newer).await;
+        assert!(!TOKENS.read().await.contains_key(&id));
+    }
+}